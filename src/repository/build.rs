@@ -1,7 +1,22 @@
+use crate::repository::cancel::CancellationToken;
+use crate::repository::collision;
+use crate::repository::collision::PathCollision;
+use crate::repository::delta;
+use crate::repository::delta::DeltaError;
+use crate::repository::hash;
+use crate::repository::history;
+use crate::repository::history::HistoryError;
+use crate::repository::manifest;
+use crate::repository::manifest::{Manifest, ManifestEntry, ManifestError};
+use crate::repository::progress::{Phase, Progress, ProgressEvent};
+use crate::repository::signing;
+use crate::repository::signing::SigningError;
+use crate::repository::store;
 use crate::sql::sqlite;
-use indextree::{Arena, NodeId};
-use std::path::Path;
-use std::time::SystemTime;
+use log::{debug, info, trace, warn};
+use std::fs::Metadata;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
 use walkdir::WalkDir;
 
 extern crate rayon;
@@ -14,12 +29,15 @@ extern crate rusqlite;
 extern crate custom_error;
 use custom_error::custom_error;
 
-use std::collections::HashMap;
+use std::collections::BTreeMap;
 
 use serde::{Deserialize, Serialize};
 use serde_json;
 
+use flate2::write::GzEncoder;
+use flate2::Compression;
 use std::fs::File;
+use std::io::{BufWriter, Write};
 
 use delta_patch::mksum::SignatureOptions;
 
@@ -33,7 +51,16 @@ custom_error! {pub BuildRepoError
     CryptoError{source: easy_xxhash64::file_hash::CryptoError} = "Crypto Error",
     SerdeError{source: serde_json::Error} = "Serde Error",
     SystemTimeErr{source: std::time::SystemTimeError} = "System Time Error",
-    IOError{source: std::io::Error} = "IO Error"
+    IOError{source: std::io::Error} = "IO Error",
+    DeltaError{source: DeltaError} = "Delta Error",
+    ManifestError{source: ManifestError} = "Manifest Error",
+    SigningError{source: SigningError} = "Signing Error",
+    HistoryError{source: HistoryError} = "History Error",
+    PathCollision{count: usize} = "{count} paths collide on case-insensitive or normalizing file systems",
+    NonUtf8Path{path: String} = "Path is not valid UTF-8: {path}",
+    Unreadable{path: String, source: std::io::Error} = "Could not read {path}: {source}",
+    InvalidPath{path: String} = "Path is not valid on every platform: {path}",
+    Cancelled = "Build cancelled"
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -41,15 +68,30 @@ pub struct FileSystemEntity {
     pub name: String,
     pub is_folder: bool,
     pub hash: u64,
+    /// Hex encoded BLAKE2b digest, empty for folders
+    #[serde(default)]
+    pub blake2b: String,
+    /// File size in byte, 0 for folders
+    #[serde(default)]
+    pub size: u64,
+    /// Last modification as unix timestamp (nanoseconds), 0 for folders.
+    /// Seconds would miss a rewrite of the same size within the same second.
+    #[serde(default)]
+    pub mtime: u64,
 }
 impl FileSystemEntity {
-    pub fn new(name: &str, repo_path: &str) -> Result<FileSystemEntity, BuildRepoError> {
-        let is_directory = Path::is_dir(name.as_ref());
+    pub fn new(name: &Path, repo_path: &Path) -> Result<FileSystemEntity, BuildRepoError> {
+        let relative = relative_name(name, repo_path)?;
+        let meta = std::fs::metadata(name).map_err(unreadable(name))?;
+        let is_directory = meta.is_dir();
         let mut xhash: u64 = 0;
+        let mut blake2b = String::new();
         if !is_directory {
-            xhash = easy_xxhash64::file_hash::hash_path(name)?;
-            let signame: String = String::from(name) + ".a3mo_delta";
-            let mut base = File::open(&name)?;
+            xhash = hash::xxhash_path(name).map_err(unreadable(name))?;
+            blake2b = hash::blake2b_path(name).map_err(unreadable(name))?;
+            let signame = signature_path(name);
+            let mut base = File::open(name).map_err(unreadable(name))?;
+            //Failing to write the signature is a server problem, not a broken file
             let mut sig = File::create(&signame)?;
             delta_patch::mksum::generate_signature(
                 &mut base,
@@ -57,155 +99,558 @@ impl FileSystemEntity {
                 &mut sig,
             )?;
         }
-        println!("{:?}:\t{:?}", &name, &xhash);
+        debug!(target: "a3mo::build", "{:?}:\t{:?}", &name, &xhash);
         Ok(FileSystemEntity {
-            name: String::from(name.replace(repo_path, "").trim_start_matches('\\')),
+            name: relative,
             is_folder: is_directory,
             hash: xhash,
+            blake2b,
+            size: if is_directory { 0 } else { meta.len() },
+            mtime: if is_directory { 0 } else { mtime(&meta)? },
         })
     }
+
+    /// Reuses the hash and signature of the previous build, if size and mtime of the file are unchanged
+    /// * `name` : Full path of the file
+    /// * `previous` : Entry of the file in the previous manifest
+    /// * `repo_path` : Path of the repository folder
+    fn reuse(
+        name: &Path,
+        repo_path: &Path,
+        previous: &ManifestEntry,
+    ) -> Result<Option<FileSystemEntity>, BuildRepoError> {
+        let relative = relative_name(name, repo_path)?;
+        let meta = std::fs::metadata(name).map_err(unreadable(name))?;
+        if meta.is_dir() || previous.is_folder {
+            return Ok(None);
+        }
+
+        if previous.blake2b.is_empty()
+            || previous.size != meta.len()
+            || previous.mtime != mtime(&meta)?
+            || !signature_path(name).exists()
+        {
+            return Ok(None);
+        }
+
+        Ok(Some(FileSystemEntity {
+            name: relative,
+            is_folder: false,
+            hash: previous.hash,
+            blake2b: String::from(&previous.blake2b),
+            size: previous.size,
+            mtime: previous.mtime,
+        }))
+    }
 }
 
-fn build_tree(
-    _name: &str,
-    arena: &mut Arena<FileSystemEntity>,
-    repo_path: String,
-    rayon: bool,
-) -> Result<NodeId, BuildRepoError> {
-    let mut node_map: HashMap<String, NodeId> = HashMap::new();
+/// Name of `name` relative to the repository folder, in the manifest path format.
+/// Names, which clients would reject (e.g. containing ':' or '\'), are an error
+fn relative_name(name: &Path, repo_path: &Path) -> Result<String, BuildRepoError> {
+    let mut components: Vec<&str> = Vec::new();
+    for c in name.strip_prefix(repo_path).unwrap_or(name).components() {
+        match c.as_os_str().to_str() {
+            Some(v) => components.push(v),
+            None => {
+                return Err(BuildRepoError::NonUtf8Path {
+                    path: name.to_string_lossy().into_owned(),
+                })
+            }
+        }
+    }
 
-    let mut root_node: Option<NodeId> = None;
+    let relative = components.join("/");
+    if !relative.is_empty() && manifest::check_path(&relative).is_err() {
+        return Err(BuildRepoError::InvalidPath {
+            path: name.to_string_lossy().into_owned(),
+        });
+    }
 
-    //Insert nodes
+    Ok(relative)
+}
 
-    if rayon {
-        let entries: Vec<std::result::Result<walkdir::DirEntry, walkdir::Error>> =
-            WalkDir::new(&repo_path).into_iter().collect();
-        let fsxe_s: Vec<FileSystemEntity> = entries
-            .par_iter()
-            .map(|p| {
-                let fa: &std::result::Result<walkdir::DirEntry, walkdir::Error> = p;
-                let f = fa.as_ref().unwrap();
+/// Attaches `path` to an IO error, which occurred while reading it
+fn unreadable(path: &Path) -> impl Fn(std::io::Error) -> BuildRepoError + '_ {
+    move |source| BuildRepoError::Unreadable {
+        path: path.to_string_lossy().into_owned(),
+        source,
+    }
+}
 
-                let fname = f.path().to_str().unwrap();
-                FileSystemEntity::new(fname, &repo_path.as_str()).unwrap()
-            })
-            .collect();
+/// Converts an error of the directory walk, e.g. a folder without read permission
+fn walk_error(e: walkdir::Error) -> BuildRepoError {
+    let path = e.path().map(|p| p.to_string_lossy().into_owned());
+    match (path, e.io_error().is_some()) {
+        (Some(path), true) => BuildRepoError::Unreadable {
+            path,
+            source: e.into_io_error().unwrap(),
+        },
+        _ => e.into(),
+    }
+}
 
-        for fse in fsxe_s {
-            let fse_s = String::from(&fse.name);
+/// Path, which was left out of the build
+#[derive(Debug)]
+pub struct SkippedPath {
+    pub path: String,
+    pub reason: String,
+}
 
-            let node_id = arena.new_node(fse);
+/// Checks if `e` only concerns a single path, which can be left out of the build
+fn is_skippable(e: &BuildRepoError) -> Option<&str> {
+    match e {
+        BuildRepoError::NonUtf8Path { path }
+        | BuildRepoError::InvalidPath { path }
+        | BuildRepoError::Unreadable { path, .. } => Some(path),
+        _ => None,
+    }
+}
 
-            if root_node.is_none() {
-                root_node = Some(node_id);
-            }
-            node_map.insert(fse_s, node_id);
+/// Path of the delta signature of the file at `name`
+fn signature_path(name: &Path) -> PathBuf {
+    store::append_suffix(name, ".a3mo_delta")
+}
+
+fn mtime(meta: &Metadata) -> Result<u64, BuildRepoError> {
+    Ok(meta.modified()?.duration_since(UNIX_EPOCH)?.as_nanos() as u64)
+}
+
+/// Lists every file and folder inside the repository folder
+fn walk(repo_path: &Path, progress: &dyn Progress) -> Vec<walkdir::Result<walkdir::DirEntry>> {
+    progress.event(ProgressEvent::Phase(Phase::Scanning));
+
+    WalkDir::new(repo_path)
+        .min_depth(1)
+        .into_iter()
+        .filter_entry(|e| {
+            e.file_name() != ".a3mo" && !e.file_name().to_string_lossy().ends_with(".a3mo_delta")
+        })
+        .collect()
+}
+
+/// Hashes every walked file of the repository folder.
+/// Returns the entries and the paths left out, if `skip_unreadable` is set
+#[allow(clippy::too_many_arguments)]
+fn build_tree(
+    repo_path: &Path,
+    entries: Vec<walkdir::Result<walkdir::DirEntry>>,
+    previous: &BTreeMap<String, ManifestEntry>,
+    prev_path: &Path,
+    full: bool,
+    rayon: bool,
+    skip_unreadable: bool,
+    progress: &dyn Progress,
+    cancel: &CancellationToken,
+) -> Result<(BTreeMap<String, ManifestEntry>, Vec<SkippedPath>), BuildRepoError> {
+    let total_bytes: u64 = entries
+        .iter()
+        .filter_map(|e| e.as_ref().ok())
+        .filter_map(|e| e.metadata().ok())
+        .filter(|m| m.is_file())
+        .map(|m| m.len())
+        .sum();
+    progress.event(ProgressEvent::FilesDiscovered(entries.len()));
+    progress.event(ProgressEvent::Totals {
+        files: entries.len(),
+        bytes: Some(total_bytes),
+    });
+
+    progress.event(ProgressEvent::Phase(Phase::Hashing));
+
+    let hash_entry = |fname: &Path| -> Result<FileSystemEntity, BuildRepoError> {
+        if cancel.is_cancelled() {
+            return Err(BuildRepoError::Cancelled);
         }
-    } else {
-        for entry in WalkDir::new(&repo_path) {
-            let f = entry?;
 
-            let fname = f.path().to_str().unwrap();
+        if let Some(prev) = previous.get(&relative_name(fname, repo_path)?) {
+            if !full {
+                if let Some(fse) = FileSystemEntity::reuse(fname, repo_path, prev)? {
+                    trace!(target: "a3mo::build", "Unchanged {:?}", &fse.name);
+                    progress.event(ProgressEvent::HashFinished {
+                        name: fname.to_string_lossy().into_owned(),
+                        hash: fse.hash,
+                    });
+                    return Ok(fse);
+                }
+            }
 
-            let fse = FileSystemEntity::new(fname, &repo_path.as_str())?;
+            //The signature of the previous version is required to generate a patch
+            //Kept from a failed build, the signature next to the file may already belong to the new version
+            let signame = signature_path(fname);
+            let prev_signame = prev_path.join(format!("{}.sig", prev.hash));
+            if !prev.is_folder && signame.exists() && !prev_signame.exists() {
+                std::fs::rename(&signame, prev_signame)?;
+            }
+        }
 
-            let fse_s = String::from(&fse.name);
+        progress.event(ProgressEvent::HashStarted {
+            name: fname.to_string_lossy().into_owned(),
+        });
+        let fse = FileSystemEntity::new(fname, repo_path)?;
+        progress.event(ProgressEvent::HashFinished {
+            name: fname.to_string_lossy().into_owned(),
+            hash: fse.hash,
+        });
+        Ok(fse)
+    };
 
-            let node_id = arena.new_node(fse);
+    let hash_result = |entry: walkdir::Result<walkdir::DirEntry>| match entry {
+        Ok(f) => hash_entry(f.path()),
+        Err(e) => Err(walk_error(e)),
+    };
 
-            if root_node.is_none() {
-                root_node = Some(node_id);
+    let results: Vec<Result<FileSystemEntity, BuildRepoError>> = if rayon && skip_unreadable {
+        entries.into_par_iter().map(hash_result).collect()
+    } else if rayon {
+        //Stop at the first error
+        entries
+            .into_par_iter()
+            .map(hash_result)
+            .collect::<Result<Vec<FileSystemEntity>, BuildRepoError>>()?
+            .into_iter()
+            .map(Ok)
+            .collect()
+    } else {
+        let mut results: Vec<Result<FileSystemEntity, BuildRepoError>> = Vec::new();
+        for entry in entries {
+            let result = hash_result(entry);
+            let failed = result.is_err();
+            results.push(result);
+            if failed && !skip_unreadable {
+                break;
             }
+        }
+        results
+    };
 
-            node_map.insert(fse_s, node_id);
+    let mut tree: BTreeMap<String, ManifestEntry> = BTreeMap::new();
+    let mut skipped: Vec<SkippedPath> = Vec::new();
+    for result in results {
+        match result {
+            Ok(fse) => {
+                tree.insert(String::from(&fse.name), ManifestEntry::of(&fse));
+            }
+            Err(e) => match is_skippable(&e) {
+                Some(path) if skip_unreadable => {
+                    warn!(target: "a3mo::build", "Skipping {:?}: {}", path, e);
+                    skipped.push(SkippedPath {
+                        path: String::from(path),
+                        reason: e.to_string(),
+                    });
+                }
+                _ => return Err(e),
+            },
         }
     }
 
-    for n in &node_map {
-        let node_name = n.0;
-        let node_index = n.1;
+    Ok((tree, skipped))
+}
 
-        //TODO Fix for linux
-        let xf = match node_name.rfind('\\') {
-            Some(v) => v,
-            None => 0,
+/// Removes signatures, whose file no longer exists
+fn remove_old_delta(repo_path: &Path) -> Result<(), BuildRepoError> {
+    for f in WalkDir::new(repo_path) {
+        //Unreadable folders are reported by build_tree
+        let fx = match f {
+            Ok(v) => v,
+            Err(_) => continue,
         };
-        let parent_name = node_name.split_at(xf).0;
-
-        let parent = node_map.get(parent_name);
-        match parent {
-            Some(v) => {
-                //Prevent self attaching on head
-                if v == node_index {
-                    continue;
-                }
-                v.append(*node_index, arena);
-            }
-            None => continue,
+        let file_name = fx.file_name().to_string_lossy();
+
+        if file_name.ends_with(".a3mo_delta")
+            && !fx
+                .path()
+                .with_file_name(file_name.trim_end_matches(".a3mo_delta"))
+                .exists()
+        {
+            debug!(target: "a3mo::build", "{:?}", fx);
+            std::fs::remove_file(fx.path())?;
         }
     }
 
-    Ok(root_node.unwrap())
+    Ok(())
 }
 
-fn remove_old_delta(path: &str) -> Result<(), BuildRepoError> {
-    let root_path = path.trim_end_matches(".a3mo");
-    for f in WalkDir::new(root_path) {
-        let fx = f?;
-        let path = fx.path();
+/// Reads the sync.json of the previous build, if there is one
+fn read_previous(sync_folder_path: &Path) -> Option<Manifest> {
+    let path = sync_folder_path.join("sync.json");
+    if !path.exists() {
+        return None;
+    }
 
-        if path.to_str().unwrap().contains(".a3mo_delta") {
-            println!("{:?}", fx);
-            std::fs::remove_file(path)?;
+    match manifest::read(&path) {
+        Ok(v) => Some(v),
+        Err(e) => {
+            warn!(
+                target: "a3mo::build",
+                "Previous {:?} is unreadable, hashing every file without patches: {}",
+                path,
+                e
+            );
+            None
+        }
+    }
+}
+
+/// Generates a patch from the previous to the current version of every changed file
+fn build_patches(
+    repo_path: &Path,
+    previous: &BTreeMap<String, ManifestEntry>,
+    entries: &BTreeMap<String, ManifestEntry>,
+    prev_path: &Path,
+    patch_path: &Path,
+    cancel: &CancellationToken,
+) -> Result<(), BuildRepoError> {
+    std::fs::create_dir_all(patch_path)?;
+
+    for (fname, fse) in entries {
+        if fse.is_folder {
+            continue;
+        }
+
+        let old_hash = match previous.get(fname) {
+            Some(v) if !v.is_folder && v.hash != fse.hash => v.hash,
+            _ => continue,
+        };
+
+        let signame = prev_path.join(format!("{}.sig", old_hash));
+        if !signame.exists() {
+            continue;
+        }
+
+        if cancel.is_cancelled() {
+            return Err(BuildRepoError::Cancelled);
+        }
+
+        let sig = delta::Signature::parse(&std::fs::read(&signame)?)?;
+        let fpath = manifest::local_path(repo_path, fname);
+        let patchname = patch_path.join(patch_name(&old_hash.to_string(), fse.hash));
+
+        let size = {
+            let mut new_file = File::open(&fpath)?;
+            let mut patch = BufWriter::new(File::create(&patchname)?);
+            delta::generate_patch(&sig, &mut new_file, &mut patch)?
+        };
+
+        //A patch bigger than the file itself is useless
+        if size >= std::fs::metadata(&fpath)?.len() {
+            std::fs::remove_file(&patchname)?;
+        } else {
+            debug!(target: "a3mo::build", "Patch {:?}: {:?} byte", fname, &size);
         }
     }
 
     Ok(())
 }
 
+/// Writes `data` gzip compressed to `path`
+fn write_compressed(path: &Path, data: &[u8]) -> std::io::Result<()> {
+    let mut encoder = GzEncoder::new(BufWriter::new(File::create(path)?), Compression::best());
+    encoder.write_all(data)?;
+    encoder.finish()?.flush()
+}
+
+/// Name of the patch from `old_hash` to `new_hash` inside the patches folder
+pub(crate) fn patch_name(old_hash: &str, new_hash: u64) -> String {
+    format!("{}_{}.a3mo_patch", old_hash, new_hash)
+}
+
+/// Result of a build
+#[derive(Debug, Default)]
+pub struct BuildReport {
+    /// Revision published by the build
+    pub revision: u64,
+    /// Paths, which break the repository on case-insensitive or normalizing file systems
+    pub collisions: Vec<PathCollision>,
+    /// Non UTF-8 and unreadable paths, left out of the build
+    pub skipped: Vec<SkippedPath>,
+}
+
 /// (Re)build a repository
 /// * `name` : Repository name (Has to be created using new command)
 /// * `fmt_json` : Output formatted json
 /// * `rayon` : Parallelize building using rayon (requires multiple cores/threads)
-pub fn build(name: &str, fmt_json: bool, rayon: bool) -> Result<(), BuildRepoError> {
+/// * `full` : Hash every file, even if size and mtime are unchanged since the previous build
+/// * `keypair` : Path to a keypair created by signing::generate_keypair, signs the sync.json if given
+/// * `fail_on_collision` : Fail instead of publishing a repository with colliding paths (see BuildReport::collisions)
+/// * `skip_unreadable` : Leave non UTF-8, invalid (see manifest::check_path) and unreadable paths out with a warning (see BuildReport::skipped), instead of failing
+/// * `progress` : Receives progress events
+/// * `cancel` : Stops the build, the previous sync.json stays published
+#[allow(clippy::too_many_arguments)]
+pub fn build(
+    name: &str,
+    fmt_json: bool,
+    rayon: bool,
+    full: bool,
+    keypair: Option<&str>,
+    fail_on_collision: bool,
+    skip_unreadable: bool,
+    progress: &dyn Progress,
+    cancel: &CancellationToken,
+) -> Result<BuildReport, BuildRepoError> {
+    //Fail early on a broken keypair instead of after hashing
+    let keypair = match keypair {
+        Some(v) => Some(signing::read_keypair(v)?),
+        None => None,
+    };
+
     let mut conn = sqlite::get_conn()?;
     let repo = sqlite::get_repository(name, &mut conn)?;
 
-    if !Path::exists(repo.path.as_ref()) {
+    if !Path::new(&repo.path).exists() {
         return Err(BuildRepoError::FolderNotFound);
     };
 
-    println!("Building repository {:?}", &name);
+    info!(target: "a3mo::build", "Building repository {:?}", &name);
     let start = SystemTime::now();
 
-    let sync_folder_path: String = String::clone(&repo.path) + "\\.a3mo";
+    let repo_path = Path::new(&repo.path);
+    let sync_folder_path = repo_path.join(".a3mo");
+    let prev_folder_path = sync_folder_path.join("prev");
+    let patch_folder_path = sync_folder_path.join("patches");
+    let tmp_patch_folder_path = sync_folder_path.join("patches.tmp");
+
+    //Only the paths are needed, fail before hashing and before touching the published files
+    let walked = walk(repo_path, progress);
+    let paths: Vec<String> = walked
+        .iter()
+        .filter_map(|e| e.as_ref().ok())
+        .filter_map(|e| relative_name(e.path(), repo_path).ok())
+        .collect();
+    let collisions = collision::find_collisions(&paths);
+    for c in &collisions {
+        warn!(target: "a3mo::build", "{:?} collision: {:?}", c.kind, c.paths);
+    }
+    if fail_on_collision && !collisions.is_empty() {
+        return Err(BuildRepoError::PathCollision {
+            count: collisions.len(),
+        });
+    }
 
-    //Delete sync folder
+    let previous = read_previous(&sync_folder_path);
+
+    //Patches of a failed build, the published patches are replaced once the build is finished
     #[allow(unused_must_use)]
     {
-        std::fs::remove_dir_all(&sync_folder_path);
+        std::fs::remove_dir_all(&tmp_patch_folder_path);
     }
 
-    remove_old_delta(&sync_folder_path)?;
+    //Signatures of changed files are moved here to generate patches, kept until a build succeeds
+    std::fs::create_dir_all(&prev_folder_path)?;
 
-    let arena = &mut Arena::new();
+    let no_entries = BTreeMap::new();
+    let previous_entries = match &previous {
+        Some(v) => &v.entries,
+        None => &no_entries,
+    };
 
-    let _root_node = build_tree(name, arena, String::clone(&repo.path), rayon)?;
+    remove_old_delta(repo_path)?;
+
+    let built = build_tree(
+        repo_path,
+        walked,
+        previous_entries,
+        &prev_folder_path,
+        full,
+        rayon,
+        skip_unreadable,
+        progress,
+        cancel,
+    )
+    .and_then(|(entries, skipped)| {
+        if previous.is_some() {
+            progress.event(ProgressEvent::Phase(Phase::Patching));
+            build_patches(
+                repo_path,
+                previous_entries,
+                &entries,
+                &prev_folder_path,
+                &tmp_patch_folder_path,
+                cancel,
+            )?;
+        }
+        Ok((entries, skipped))
+    });
+    let (entries, skipped) = built?;
 
-    let json = if !fmt_json {
-        serde_json::to_string(&arena)?
-    } else {
-        serde_json::to_string_pretty(&arena)?
-    };
+    if cancel.is_cancelled() {
+        return Err(BuildRepoError::Cancelled);
+    }
 
-    //Create sync folder
-    std::fs::create_dir(&sync_folder_path)?;
+    progress.event(ProgressEvent::Phase(Phase::Writing));
 
-    //Save json at repo
-    std::fs::write(sync_folder_path + "\\sync.json", json)?;
+    //The history keeps counting, even if the sync.json was deleted
+    let latest = history::read_index(&sync_folder_path)?
+        .latest()
+        .map(|r| r.revision)
+        .unwrap_or(0);
+    let revision = match &previous {
+        Some(v) => v.revision.max(latest) + 1,
+        None => latest + 1,
+    };
+    let mut manifest = Manifest::new(name, revision, entries)?;
+    if let Some(v) = &keypair {
+        manifest.public_key = signing::public_key(v);
+    }
+    let json = manifest.to_json(fmt_json)?;
 
-    println!("Finished building. {:?} sec", start.elapsed()?);
+    //Create sync folder
+    std::fs::create_dir_all(&sync_folder_path)?;
+
+    //Save json at repo, replacing the previous one at once
+    let json_path = sync_folder_path.join("sync.json");
+    let tmp_json_path = sync_folder_path.join("sync.json.tmp");
+    std::fs::write(&tmp_json_path, &json)?;
+
+    let gz_path = sync_folder_path.join("sync.json.gz");
+    let tmp_gz_path = sync_folder_path.join("sync.json.gz.tmp");
+    write_compressed(&tmp_gz_path, json.as_bytes())?;
+
+    let signature = keypair.as_ref().map(|v| signing::sign(v, json.as_bytes()));
+
+    //Clients fetching between the renames see a mismatching signature and retry once
+    let sig_path = sync_folder_path.join("sync.json.sig");
+    match &signature {
+        Some(v) => {
+            let tmp_sig_path = sync_folder_path.join("sync.json.sig.tmp");
+            std::fs::write(&tmp_sig_path, v)?;
+            std::fs::rename(&tmp_sig_path, &sig_path)?;
+            std::fs::rename(&tmp_json_path, &json_path)?;
+            std::fs::rename(&tmp_gz_path, &gz_path)?;
+        }
+        None => {
+            std::fs::rename(&tmp_json_path, &json_path)?;
+            std::fs::rename(&tmp_gz_path, &gz_path)?;
+            //Signature of a previous signed build
+            std::fs::remove_file(&sig_path).unwrap_or_default();
+        }
+    }
 
-    Ok(())
+    //Swap in the patches of the published sync.json
+    let old_patch_folder_path = sync_folder_path.join("patches.old");
+    std::fs::remove_dir_all(&old_patch_folder_path).unwrap_or_default();
+    std::fs::rename(&patch_folder_path, &old_patch_folder_path).unwrap_or_default();
+    if tmp_patch_folder_path.exists() {
+        std::fs::rename(&tmp_patch_folder_path, &patch_folder_path)?;
+    }
+    std::fs::remove_dir_all(&old_patch_folder_path).unwrap_or_default();
+
+    //Only published revisions are listed
+    history::record(&sync_folder_path, &manifest, &gz_path, signature.as_deref())?;
+
+    //Signatures of the previous build are no longer needed
+    std::fs::remove_dir_all(&prev_folder_path).unwrap_or_default();
+
+    progress.event(ProgressEvent::Phase(Phase::Finished));
+    info!(
+        target: "a3mo::build",
+        "Finished building revision {:?}. {:?} sec",
+        revision,
+        start.elapsed()?
+    );
+
+    Ok(BuildReport {
+        revision,
+        collisions,
+        skipped,
+    })
 }
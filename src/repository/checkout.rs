@@ -0,0 +1,144 @@
+extern crate custom_error;
+use crate::repository::cancel::CancellationToken;
+use crate::repository::hash::ContentHash;
+use crate::repository::history;
+use crate::repository::history::HistoryError;
+use crate::repository::manifest;
+use crate::repository::manifest::Manifest;
+use crate::repository::progress::{Phase, Progress, ProgressEvent};
+use crate::repository::store;
+use crate::repository::update;
+use crate::repository::update::{UpdateError, UpdateReport};
+use crate::sql::sqlite;
+use log::{info, warn};
+use std::time::SystemTime;
+
+/// Published revision to check out
+#[derive(Debug, Clone, PartialEq)]
+pub enum Revision {
+    /// Follow the newest revision again, like a repository that was never pinned
+    Latest,
+    Number(u64),
+    /// Revision tagged by history::tag
+    Tag(String),
+}
+
+/// Files of `revision`, which are neither inside a local content store nor unchanged on the server.
+/// * `path` : Content store of the repository
+/// * `stores` : Content stores of all other repositories
+/// * `current` : Current manifest of the server
+fn unavailable_files<'a>(
+    path: &str,
+    stores: &[String],
+    revision: &'a Manifest,
+    current: &Manifest,
+) -> Vec<&'a str> {
+    let mut unavailable: Vec<&str> = Vec::new();
+
+    for (fpath, fse) in &revision.entries {
+        if fse.is_folder {
+            continue;
+        }
+
+        let blob = ContentHash::of(fse).blob_name();
+        //Blobs of older versions are migrated while downloading
+        let stored = store::blob_path(path, &blob).exists()
+            || store::blob_path(path, &fse.hash.to_string()).exists()
+            || stores.iter().any(|s| store::blob_path(s, &blob).exists());
+        let published = match current.entries.get(fpath) {
+            Some(v) => !v.is_folder && v.hash == fse.hash && v.blake2b == fse.blake2b,
+            None => false,
+        };
+
+        if !stored && !published {
+            unavailable.push(fpath);
+        }
+    }
+
+    unavailable
+}
+
+/// Pins an already cloned repository to a published revision, update skips it until `Revision::Latest` is checked out.
+/// Files still inside a local content store are restored from there, the rest is downloaded.
+/// Only files, which did not change on the server since `revision`, can be downloaded.
+/// Fails with `UpdateError::RevisionUnavailable` before changing anything, if other files are missing.
+/// The repository is not pinned, if any download failed (see UpdateReport::failures).
+/// * `name` : Repository name (as given to the clone command)
+/// * `revision` : Revision to check out
+/// * `progress` : Receives progress events
+/// * `cancel` : Stops the checkout, the pin is only changed if it finished
+pub fn checkout(
+    name: &str,
+    revision: &Revision,
+    progress: &dyn Progress,
+    cancel: &CancellationToken,
+) -> Result<UpdateReport, UpdateError> {
+    info!(target: "a3mo::update", "Checking out {:?} of repository {:?}", revision, &name);
+    let start = SystemTime::now();
+
+    let mut conn = sqlite::get_conn()?;
+    let repository = sqlite::get_repository(name, &mut conn)?;
+
+    if *revision == Revision::Latest {
+        let report = update::update_repository(&repository, &mut conn, progress, cancel)?;
+        sqlite::set_pinned_revision(repository.id, None, &conn)?;
+        return Ok(report);
+    }
+
+    progress.event(ProgressEvent::Phase(Phase::FetchingManifest));
+    let index = history::fetch_index(&repository.url)?;
+    let info = match revision {
+        Revision::Number(v) => index.find(*v),
+        Revision::Tag(v) => index.find_tag(v),
+        Revision::Latest => index.latest(),
+    }
+    .ok_or(HistoryError::RevisionNotFound)?;
+
+    let manifest =
+        manifest::fetch_revision(&repository.url, info.revision, Some(&repository.public_key))?;
+
+    //The server only serves the current content of each file
+    let current = manifest::fetch(&repository.url, Some(&repository.public_key))?;
+    let stores = store::other_stores(&repository.path, &mut conn)?;
+    let unavailable = unavailable_files(&repository.path, &stores, &manifest, &current);
+    if !unavailable.is_empty() {
+        warn!(
+            target: "a3mo::update",
+            "Revision {:?} can not be restored, missing files: {:?}",
+            manifest.revision,
+            unavailable
+        );
+        return Err(UpdateError::RevisionUnavailable {
+            revision: manifest.revision,
+            count: unavailable.len(),
+        });
+    }
+
+    let mut report = update::sync_to(&repository, &manifest, &mut conn, progress, cancel)?;
+
+    if report.failures.is_empty() {
+        sqlite::set_pinned_revision(repository.id, Some(manifest.revision), &conn)?;
+        report.pinned = true;
+    } else {
+        warn!(
+            target: "a3mo::update",
+            "{:?} files of revision {:?} could not be restored, the repository is not pinned",
+            report.failures.len(),
+            manifest.revision
+        );
+    }
+
+    info!(
+        target: "a3mo::update",
+        "Finished checking out revision {:?}. {:?} added, {:?} modified, {:?} removed, {:?} moved, {:?} byte in {:?} sec",
+        manifest.revision,
+        report.added.len(),
+        report.modified.len(),
+        report.removed.len(),
+        report.moved.len(),
+        report.downloaded_bytes,
+        start.elapsed()?
+    );
+
+    Ok(report)
+}
@@ -1,17 +1,28 @@
 use url::Url;
 extern crate custom_error;
-use crate::repository::build::FileSystemEntity;
+use crate::repository::build;
+use crate::repository::cancel::CancellationToken;
+use crate::repository::delta;
+use crate::repository::hash::ContentHash;
+use crate::repository::manifest;
+use crate::repository::manifest::{ManifestEntry, ManifestError};
+use crate::repository::progress::{Phase, Progress, ProgressEvent};
+use crate::repository::store;
 use crate::sql;
 use crate::sql::sqlite;
 use custom_error::custom_error;
-use indextree::Arena;
+use log::{debug, info, warn};
 use rayon::prelude::*;
 use reqwest;
-use std::fs::File;
-use std::io::Seek;
-use std::path::Path;
+use reqwest::header::RANGE;
+use reqwest::StatusCode;
+use rusqlite::Connection;
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
 use std::time::SystemTime;
-use std::{fs, io};
 
 custom_error! {pub CloneError
     FolderNotFound = "Folder not found!",
@@ -24,24 +35,61 @@ custom_error! {pub CloneError
     SystemTimeErr{source: std::time::SystemTimeError} = "System Time Error",
     IOError{source: std::io::Error} = "IO Error",
     ParseError{source: url::ParseError} = "Parse Error",
-    RequestError{source: reqwest::Error} = "Request Error"
+    RequestError{source: reqwest::Error} = "Request Error",
+    HashMismatch = "Downloaded file does not match the expected hash",
+    ManifestError{source: ManifestError} = "Manifest Error",
+    Cancelled = "Clone cancelled"
 }
 
+/// Maximum attempts to download a single file
+const MAX_DOWNLOAD_ATTEMPTS: u32 = 3;
+
+/// File, which could not be downloaded
+#[derive(Debug)]
+pub struct DownloadFailure {
+    pub url: String,
+    pub path: String,
+    pub reason: String,
+}
+
+/// Bytes downloaded between two progress events of a file
+const PROGRESS_INTERVAL: u64 = 1 << 20;
+
 /// Clone an remote repository.
 /// * `path` : Path where the data will be stored (can be relative or absolute)
 /// * `url` : URL to the a3mo folder
 /// * `name` : Repo name
-pub fn clone(path: &str, url: &str, name: &str) -> Result<(), CloneError> {
+/// * `progress` : Receives progress events
+/// * `cancel` : Stops the clone and removes the repository from the database.
+///   Files already downloaded are kept, cloning again with the same name and path continues the downloads
+///
+/// Returns all files, which could not be downloaded
+pub fn clone(
+    path: &str,
+    url: &str,
+    name: &str,
+    progress: &dyn Progress,
+    cancel: &CancellationToken,
+) -> Result<Vec<DownloadFailure>, CloneError> {
     Url::parse(url)?;
 
-    println!("Cloning repository {:?}", &name);
+    info!(target: "a3mo::clone", "Cloning repository {:?}", &name);
     let start = SystemTime::now();
 
-    let sync_url = url.to_owned() + "/sync.json";
-    let mut sync_json_resp = reqwest::get(&sync_url)?;
-    let jstring = sync_json_resp.text()?;
+    progress.event(ProgressEvent::Phase(Phase::FetchingManifest));
+    let manifest = manifest::fetch(url, None)?;
+    info!(
+        target: "a3mo::clone",
+        "Remote revision {:?} built by {:?}",
+        manifest.revision,
+        &manifest.generator
+    );
 
-    let arena: Arena<FileSystemEntity> = serde_json::from_str(jstring.as_str())?;
+    if cancel.is_cancelled() {
+        return Err(CloneError::Cancelled);
+    }
+
+    progress.event(ProgressEvent::Phase(Phase::UpdatingDatabase));
 
     let mut conn = sqlite::get_conn()?;
 
@@ -49,135 +97,347 @@ pub fn clone(path: &str, url: &str, name: &str) -> Result<(), CloneError> {
 
     let repository = sql::sqlite::get_repository(name, &mut conn)?;
 
-    // Insert new repo into db
-    for fse_node in arena.iter() {
-        let fse = fse_node.get();
-        let parent_node = match fse_node.parent() {
-            Some(v) => arena.get(v),
-            None => None,
-        };
+    //Trust on first use, updates have to be signed by the same key
+    if !manifest.public_key.is_empty() {
+        sql::sqlite::set_public_key(repository.id, &manifest.public_key, &conn)?;
+    }
 
-        if fse.is_folder {
-            match parent_node {
-                Some(v) => {
-                    let parent_id =
-                        sql::sqlite::get_folder_parent_id(v.get(), repository.id, &conn)?;
-                    //println!("SOME FOLDER INSERT {:?} -> {:?}[{:?}]", &fse, &v.get(), &parent_id);
-                    sql::sqlite::insert_folder(
-                        fse.name.as_str(),
-                        repository.id,
-                        Some(parent_id),
-                        &conn,
-                    )?;
-                }
-                None => {
-                    //println!("NONE FOLDER INSERT {:?}", &fse);
-                    sql::sqlite::insert_folder(fse.name.as_str(), repository.id, None, &conn)?;
-                }
-            }
-        } else {
-            match parent_node {
-                Some(v) => {
-                    let parent_id = sql::sqlite::get_file_parent_id(v.get(), repository.id, &conn)?;
-
-                    println!(
-                        "SOME FILE INSERT {:?} -> {:?}[{:?}]",
-                        &fse,
-                        &v.get(),
-                        &parent_id
-                    );
-                    sql::sqlite::insert_file(
-                        fse.name.as_str(),
-                        fse.hash,
-                        repository.id,
-                        parent_id,
-                        &conn,
-                    )?;
-                }
-                None => {
-                    //This should never happen, every file has atleast "" as root
-                    return Err(CloneError::FileParentError);
-                }
-            }
-        }
+    // Insert new repo into db, parents are sorted before their children
+    let tx = conn.transaction()?;
+    sql::sqlite::insert_folder("", repository.id, None, &tx)?;
+    for (fpath, fse) in &manifest.entries {
+        insert_node(fpath, fse, repository.id, &tx)?;
     }
 
+    if cancel.is_cancelled() {
+        tx.rollback()?;
+        sql::sqlite::delete_repository(repository.id, &conn)?;
+        return Err(CloneError::Cancelled);
+    }
+    tx.commit()?;
+
     //Download missing files
+    let stores = store::other_stores(path, &mut conn)?;
+    let result = download_missing(
+        path,
+        url,
+        &manifest.entries,
+        &HashMap::new(),
+        &stores,
+        progress,
+        cancel,
+    );
+
+    //The name stays free for the next clone
+    if cancel.is_cancelled() || result.is_err() {
+        sql::sqlite::delete_repository(repository.id, &conn)?;
+    }
+    let (size, failures) = result?;
+    if cancel.is_cancelled() {
+        return Err(CloneError::Cancelled);
+    }
+
+    let elapsed = start.elapsed()?;
+    info!(
+        target: "a3mo::clone",
+        "Finished cloning. {:?} byte in {:?} sec ({:.2} MB/s)",
+        &size,
+        &elapsed,
+        //Clones reusing other stores finish within a second
+        size as f64 / elapsed.as_secs_f64().max(0.001) / 1_000_000.0
+    );
+    progress.event(ProgressEvent::Phase(Phase::Finished));
+
+    Ok(failures)
+}
+
+/// Inserts a single manifest entry as folder or file row, its parent has to be inserted already
+pub(crate) fn insert_node(
+    path: &str,
+    fse: &ManifestEntry,
+    repo_id: i64,
+    conn: &Connection,
+) -> Result<(), CloneError> {
+    let parent_name = manifest::parent_path(path);
+
+    if fse.is_folder {
+        let parent_id = sql::sqlite::get_folder_parent_id(parent_name, repo_id, conn)?;
+        sql::sqlite::insert_folder(path, repo_id, Some(parent_id), conn)?;
+    } else {
+        let parent_id = match sql::sqlite::get_file_parent_id(parent_name, repo_id, conn) {
+            Ok(v) => v,
+            //Every file has atleast "" as root
+            Err(_) => return Err(CloneError::FileParentError),
+        };
+
+        debug!(
+            target: "a3mo::clone",
+            "FILE INSERT {:?} -> {:?}[{:?}]",
+            path,
+            parent_name,
+            &parent_id
+        );
+        sql::sqlite::insert_file(path, fse.hash, &fse.blake2b, repo_id, parent_id, conn)?;
+    }
+
+    Ok(())
+}
+
+#[derive(Debug)]
+pub(crate) struct DownloadTask {
+    name: String,
+    url: String,
+    filepath: PathBuf,
+    hash: ContentHash,
+    /// Previous version of the file and the url of the prebuilt patch from it
+    base: Option<(PathBuf, String)>,
+}
+
+impl DownloadTask {
+    /// Task downloading the file `name` of the repository at `url` into the content store at `path`
+    pub(crate) fn new(
+        path: &str,
+        url: &str,
+        name: &str,
+        hash: ContentHash,
+    ) -> Result<DownloadTask, CloneError> {
+        let url_p = Url::parse(url)?;
+
+        let xpath = "../".to_owned() + name;
+        let uri = url_p.join(&xpath)?;
+
+        Ok(DownloadTask {
+            name: String::from(name),
+            url: uri.to_string(),
+            filepath: store::blob_path(path, &hash.blob_name()),
+            hash,
+            base: None,
+        })
+    }
+}
+
+/// Downloads every file of the manifest, which is not yet inside `path`
+/// Files with an entry in `bases` (file name -> (previous blob, previous xxHash64)) are patched from their previous version if possible,
+/// using a prebuilt patch or the remote signature.
+/// Files already inside one of the content stores at `stores` are copied from there.
+/// Returns the amount of downloaded bytes and all failed downloads
+pub(crate) fn download_missing(
+    path: &str,
+    url: &str,
+    entries: &BTreeMap<String, ManifestEntry>,
+    bases: &HashMap<String, (String, String)>,
+    stores: &[String],
+    progress: &dyn Progress,
+    cancel: &CancellationToken,
+) -> Result<(u64, Vec<DownloadFailure>), CloneError> {
+    progress.event(ProgressEvent::Phase(Phase::Downloading));
 
     if !Path::new(&path).exists() {
         fs::create_dir_all(&path)?;
     }
 
-    let mut to_download: Vec<(String, String)> = Vec::new();
+    let mut to_download: Vec<DownloadTask> = Vec::new();
 
-    for fse_node in arena.iter() {
-        let fse = fse_node.get();
+    for (fpath, fse) in entries {
         if !fse.is_folder {
-            let fullpath = path.to_owned() + "\\" + &fse.hash.to_string();
-
-            if !Path::new(&fullpath).exists() {
-                let url_p = Url::parse(url)?;
+            let mut task = DownloadTask::new(path, url, fpath, ContentHash::of(fse))?;
 
-                let xpath = "../".to_owned() + &fse.name;
-                let uri = url_p.join(&xpath)?;
+            if !task.filepath.exists()
+                && !store::migrate_blob(path, &task.hash)?
+                && !store::import_blob(stores, path, &task.hash)?
+            {
+                debug!(target: "a3mo::clone", "PUSH {:?} -> {:?}", fpath, &task);
 
-                println!("PUSH {:?} -> {:?} || {:?}", &fse, &uri, &fullpath);
+                if let Some((blob, xx_hash)) = bases.get(fpath) {
+                    let basepath = store::blob_path(path, blob);
+                    if basepath.exists() {
+                        let patch_url =
+                            url.to_owned() + "/patches/" + &build::patch_name(xx_hash, fse.hash);
+                        task.base = Some((basepath, patch_url));
+                    }
+                }
 
-                to_download.push((uri.to_string(), fullpath));
+                to_download.push(task);
             } else {
-                println!("Skip {:?}", &fse);
+                debug!(target: "a3mo::clone", "Skip {:?}", fpath);
             }
         }
     }
 
-    let _x: Vec<u64> = to_download
-        .par_iter()
-        .map(|c| {
-            println!("Downloading {:?}", &c);
+    Ok(download(&to_download, progress, cancel))
+}
 
-            let url: &String = &c.0;
-            let filepath: &String = &c.1;
-            let resp = reqwest::get(url.as_str());
-            let out = File::create(filepath);
+/// Downloads `url` into `filepath` and checks it against `hash`.
+/// The data is written to a partial file first, an interrupted or cancelled download continues where it stopped.
+/// Returns the amount of downloaded bytes
+fn download_file(
+    url: &str,
+    filepath: &Path,
+    hash: &ContentHash,
+    name: &str,
+    progress: &dyn Progress,
+    cancel: &CancellationToken,
+) -> Result<u64, CloneError> {
+    let partpath = store::partial_path(filepath);
+    let offset = match fs::metadata(&partpath) {
+        Ok(v) => v.len(),
+        Err(_) => 0,
+    };
 
-            if resp.is_err() {
-                println!("Could not download {:?} Err: {:?}", url, resp.err());
-                return 0;
-            }
+    let client = reqwest::Client::new();
+    let mut req = client.get(url);
+    if offset > 0 {
+        req = req.header(RANGE, format!("bytes={}-", offset));
+    }
+    let resp = req.send()?;
 
-            if out.is_err() {
-                println!("Could create file {:?} Err: {:?}", filepath, out.err());
-                return 0;
-            }
+    //Partial file is broken or already complete, start over
+    if offset > 0 && resp.status() == StatusCode::RANGE_NOT_SATISFIABLE {
+        fs::remove_file(&partpath)?;
+        return download_file(url, filepath, hash, name, progress, cancel);
+    }
+    let mut resp = resp.error_for_status()?;
 
-            let mut uw_resp = resp.unwrap();
-            let mut uw_out = out.unwrap();
+    let resumed = offset > 0 && resp.status() == StatusCode::PARTIAL_CONTENT;
+    let mut out = if resumed {
+        OpenOptions::new().append(true).open(&partpath)?
+    } else {
+        //Server ignored the range request
+        File::create(&partpath)?
+    };
+    let start = if resumed { offset } else { 0 };
+    let total = resp.content_length().map(|v| v + start);
 
-            let iocp = io::copy(&mut uw_resp, &mut uw_out);
-            if iocp.is_err() {
-                println!("Could copy {:?} Err: {:?}", filepath, iocp.err());
-                return 0;
-            }
+    let mut buf = vec![0u8; 1 << 16];
+    let mut downloaded: u64 = 0;
+    let mut reported: u64 = 0;
+    loop {
+        if cancel.is_cancelled() {
+            return Err(CloneError::Cancelled);
+        }
+
+        let read = resp.read(&mut buf)?;
+        if read == 0 {
+            break;
+        }
+        out.write_all(&buf[..read])?;
+        downloaded += read as u64;
 
-            let stpos = uw_out.stream_position();
+        if downloaded - reported >= PROGRESS_INTERVAL {
+            progress.event(ProgressEvent::Downloading {
+                name: String::from(name),
+                downloaded: start + downloaded,
+                total,
+            });
+            reported = downloaded;
+        }
+    }
+    out.flush()?;
+    drop(out);
+
+    if !hash.matches(&partpath)? {
+        fs::remove_file(&partpath)?;
+        return Err(CloneError::HashMismatch);
+    }
+
+    fs::rename(&partpath, filepath)?;
+
+    Ok(downloaded)
+}
 
-            if stpos.is_err() {
-                println!("Could get stpos {:?} Err: {:?}", filepath, stpos.err());
-                return 0;
+/// Downloads a single task, patching it from its base if possible
+fn download_task(
+    c: &DownloadTask,
+    progress: &dyn Progress,
+    cancel: &CancellationToken,
+) -> Result<u64, CloneError> {
+    debug!(target: "a3mo::clone", "Downloading {:?}", &c);
+
+    let url: &String = &c.url;
+    let filepath: &Path = &c.filepath;
+
+    if let Some((base, patch_url)) = &c.base {
+        match delta::download_patch(patch_url, base, filepath, &c.hash) {
+            Ok(v) => return Ok(v),
+            Err(e) => {
+                debug!(target: "a3mo::clone", "No usable patch for {:?} Err: {:?}", filepath, e)
             }
+        }
+        match delta::download_delta(url, base, filepath, &c.hash) {
+            Ok(v) => return Ok(v),
+            Err(e) => info!(
+                target: "a3mo::clone",
+                "Delta update of {:?} failed, downloading complete file. Err: {:?}",
+                filepath, e
+            ),
+        }
+    }
 
-            stpos.unwrap()
+    download_file(url, filepath, &c.hash, &c.name, progress, cancel)
+}
+
+/// Downloads all tasks in parallel, every task is retried up to MAX_DOWNLOAD_ATTEMPTS times.
+/// After cancellation no new task is started, they are returned as failed.
+/// Returns the amount of downloaded bytes and all failed downloads
+pub(crate) fn download(
+    tasks: &[DownloadTask],
+    progress: &dyn Progress,
+    cancel: &CancellationToken,
+) -> (u64, Vec<DownloadFailure>) {
+    progress.event(ProgressEvent::Totals {
+        files: tasks.len(),
+        bytes: None,
+    });
+
+    let results: Vec<Result<u64, DownloadFailure>> = tasks
+        .par_iter()
+        .map(|c| {
+            let mut attempt = 1;
+            loop {
+                if cancel.is_cancelled() {
+                    return Err(DownloadFailure {
+                        url: String::from(&c.url),
+                        path: c.filepath.to_string_lossy().into_owned(),
+                        reason: CloneError::Cancelled.to_string(),
+                    });
+                }
+
+                match download_task(c, progress, cancel) {
+                    Ok(v) => {
+                        progress.event(ProgressEvent::DownloadFinished {
+                            name: String::from(&c.name),
+                            bytes: v,
+                        });
+                        return Ok(v);
+                    }
+                    Err(e) => {
+                        warn!(
+                            target: "a3mo::clone",
+                            "Could not download {:?} (attempt {:?}/{:?}) Err: {:?}",
+                            &c.url, attempt, MAX_DOWNLOAD_ATTEMPTS, e
+                        );
+                        if attempt >= MAX_DOWNLOAD_ATTEMPTS {
+                            return Err(DownloadFailure {
+                                url: String::from(&c.url),
+                                path: c.filepath.to_string_lossy().into_owned(),
+                                reason: e.to_string(),
+                            });
+                        }
+                    }
+                }
+                attempt += 1;
+            }
         })
         .collect();
 
-    let elapsed = start.elapsed()?;
-    let size: u64 = _x.iter().sum();
-    println!(
-        "Finished cloning. {:?} byte in {:?} sec ({:?} MB/s)",
-        &size,
-        &elapsed,
-        (size / elapsed.as_secs()) / 1_000_000
-    );
+    let mut size: u64 = 0;
+    let mut failures: Vec<DownloadFailure> = Vec::new();
+    for r in results {
+        match r {
+            Ok(v) => size += v,
+            Err(e) => failures.push(e),
+        }
+    }
 
-    Ok(())
+    (size, failures)
 }
@@ -0,0 +1,131 @@
+use std::collections::{BTreeMap, BTreeSet};
+use unicode_normalization::UnicodeNormalization;
+
+/// Reason two paths of a repository collide
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CollisionKind {
+    /// Paths only differ in case, e.g. ACE_Common.pbo and ace_common.pbo
+    Case,
+    /// Paths only differ in their Unicode normalization form (NFC/NFD)
+    UnicodeNormalization,
+}
+
+/// Paths, which end up as the same file on a case-insensitive or normalizing file system
+#[derive(Debug, Clone)]
+pub struct PathCollision {
+    pub kind: CollisionKind,
+    pub paths: Vec<String>,
+}
+
+fn file_name(path: &str) -> &str {
+    match path.rfind('/') {
+        Some(v) => &path[v + 1..],
+        None => path,
+    }
+}
+
+/// Finds all `paths` (in the manifest path format), which collide under case folding or Unicode normalization.
+/// Collisions of files inside colliding folders are only reported, if the file names collide themselves.
+pub(crate) fn find_collisions(paths: &[String]) -> Vec<PathCollision> {
+    // Folded path -> paths
+    let mut groups: BTreeMap<String, Vec<&String>> = BTreeMap::new();
+    for path in paths {
+        let folded = path.nfc().collect::<String>().to_lowercase();
+        groups.entry(folded).or_default().push(path);
+    }
+
+    let mut collisions: Vec<PathCollision> = Vec::new();
+    for paths in groups.values_mut() {
+        if paths.len() < 2 {
+            continue;
+        }
+
+        paths.sort();
+        let names: BTreeSet<&str> = paths.iter().map(|p| file_name(p)).collect();
+        if names.len() < 2 {
+            continue;
+        }
+
+        let normalized: BTreeSet<String> = paths.iter().map(|p| p.nfc().collect()).collect();
+        collisions.push(PathCollision {
+            kind: if normalized.len() > 1 {
+                CollisionKind::Case
+            } else {
+                CollisionKind::UnicodeNormalization
+            },
+            paths: paths.iter().map(|p| p.to_string()).collect(),
+        });
+    }
+
+    collisions
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn paths(p: &[&str]) -> Vec<String> {
+        p.iter().map(|v| String::from(*v)).collect()
+    }
+
+    #[test]
+    fn no_collision() {
+        let c = find_collisions(&paths(&[
+            "@ace",
+            "@ace/addons",
+            "@ace/addons/ace_common.pbo",
+        ]));
+        assert!(c.is_empty());
+    }
+
+    #[test]
+    fn case() {
+        let c = find_collisions(&paths(&[
+            "@ace/addons/ace_common.pbo",
+            "@ace/addons/ACE_Common.pbo",
+        ]));
+        assert_eq!(c.len(), 1);
+        assert_eq!(c[0].kind, CollisionKind::Case);
+        assert_eq!(
+            c[0].paths,
+            paths(&["@ace/addons/ACE_Common.pbo", "@ace/addons/ace_common.pbo"])
+        );
+    }
+
+    #[test]
+    fn unicode_normalization() {
+        // NFC and NFD form of "é"
+        let c = find_collisions(&paths(&["caf\u{e9}.pbo", "cafe\u{301}.pbo"]));
+        assert_eq!(c.len(), 1);
+        assert_eq!(c[0].kind, CollisionKind::UnicodeNormalization);
+        assert_eq!(c[0].paths.len(), 2);
+    }
+
+    #[test]
+    fn case_and_normalization() {
+        let c = find_collisions(&paths(&["CAF\u{c9}", "cafe\u{301}"]));
+        assert_eq!(c.len(), 1);
+        assert_eq!(c[0].kind, CollisionKind::Case);
+    }
+
+    #[test]
+    fn inside_colliding_folder() {
+        let c = find_collisions(&paths(&[
+            "@ACE",
+            "@ace",
+            "@ACE/a.pbo",
+            "@ace/a.pbo",
+            "@ace/B.pbo",
+            "@ACE/b.pbo",
+        ]));
+        let collided: Vec<&Vec<String>> = c.iter().map(|v| &v.paths).collect();
+        // a.pbo only collides because of its folder
+        assert_eq!(
+            collided,
+            vec![
+                &paths(&["@ACE", "@ace"]),
+                &paths(&["@ACE/b.pbo", "@ace/B.pbo"])
+            ]
+        );
+    }
+}
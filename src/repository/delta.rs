@@ -0,0 +1,673 @@
+extern crate custom_error;
+use crate::repository::hash::ContentHash;
+use crate::repository::store;
+use custom_error::custom_error;
+use reqwest;
+use reqwest::header::RANGE;
+use reqwest::StatusCode;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+custom_error! {pub DeltaError
+    InvalidSignature = "Invalid signature",
+    UnsupportedSignature = "Unsupported signature format",
+    SignatureNotFound = "Signature not found",
+    PatchNotFound = "Patch not found",
+    InvalidPatch = "Invalid patch",
+    RangeNotSupported = "Server does not support range requests",
+    HashMismatch = "Patched file does not match the expected hash",
+    IOError{source: std::io::Error} = "IO Error",
+    CryptoError{source: easy_xxhash64::file_hash::CryptoError} = "Crypto Error",
+    RequestError{source: reqwest::Error} = "Request Error"
+}
+
+/// librsync signature magic for BLAKE2 strong sums (the format written by delta_patch::mksum)
+const BLAKE2_SIG_MAGIC: u32 = 0x7273_0137;
+const BLAKE2_SUM_LENGTH: usize = 32;
+const ROLLSUM_CHAR_OFFSET: u16 = 31;
+
+/// Rolling checksum, compatible with the librsync weak sum
+struct Rollsum {
+    count: u16,
+    s1: u16,
+    s2: u16,
+}
+
+impl Rollsum {
+    fn new(data: &[u8]) -> Rollsum {
+        let mut sum = Rollsum {
+            count: 0,
+            s1: 0,
+            s2: 0,
+        };
+        for b in data {
+            sum.s1 = sum.s1.wrapping_add(u16::from(*b) + ROLLSUM_CHAR_OFFSET);
+            sum.s2 = sum.s2.wrapping_add(sum.s1);
+            sum.count = sum.count.wrapping_add(1);
+        }
+        sum
+    }
+
+    fn rotate(&mut self, out: u8, inb: u8) {
+        self.s1 = self
+            .s1
+            .wrapping_add(u16::from(inb))
+            .wrapping_sub(u16::from(out));
+        self.s2 = self.s2.wrapping_add(self.s1).wrapping_sub(
+            self.count
+                .wrapping_mul(u16::from(out) + ROLLSUM_CHAR_OFFSET),
+        );
+    }
+
+    fn rollout(&mut self, out: u8) {
+        self.s1 = self.s1.wrapping_sub(u16::from(out) + ROLLSUM_CHAR_OFFSET);
+        self.s2 = self.s2.wrapping_sub(
+            self.count
+                .wrapping_mul(u16::from(out) + ROLLSUM_CHAR_OFFSET),
+        );
+        self.count = self.count.wrapping_sub(1);
+    }
+
+    fn digest(&self) -> u32 {
+        (u32::from(self.s2) << 16) | u32::from(self.s1)
+    }
+}
+
+/// Parsed .a3mo_delta file
+pub(crate) struct Signature {
+    pub block_len: usize,
+    pub block_count: usize,
+    strong_len: usize,
+    blocks: HashMap<u32, Vec<(usize, Vec<u8>)>>,
+}
+
+impl Signature {
+    pub fn parse(data: &[u8]) -> Result<Signature, DeltaError> {
+        if data.len() < 12 {
+            return Err(DeltaError::InvalidSignature);
+        }
+        if read_u32(&data[0..4]) != BLAKE2_SIG_MAGIC {
+            return Err(DeltaError::UnsupportedSignature);
+        }
+        let block_len = read_u32(&data[4..8]) as usize;
+        let strong_len = read_u32(&data[8..12]) as usize;
+        if block_len == 0 || strong_len == 0 || strong_len > BLAKE2_SUM_LENGTH {
+            return Err(DeltaError::InvalidSignature);
+        }
+
+        let entries = &data[12..];
+        if entries.len() % (4 + strong_len) != 0 {
+            return Err(DeltaError::InvalidSignature);
+        }
+
+        let mut blocks: HashMap<u32, Vec<(usize, Vec<u8>)>> = HashMap::new();
+        let mut block_count = 0;
+        for entry in entries.chunks(4 + strong_len) {
+            let weak = read_u32(&entry[0..4]);
+            blocks
+                .entry(weak)
+                .or_default()
+                .push((block_count, entry[4..].to_vec()));
+            block_count += 1;
+        }
+
+        Ok(Signature {
+            block_len,
+            block_count,
+            strong_len,
+            blocks,
+        })
+    }
+
+    /// Returns the index of the block matching `data`
+    fn find(&self, weak: u32, data: &[u8]) -> Option<usize> {
+        let candidates = self.blocks.get(&weak)?;
+        let strong = blake2b_simd::Params::new()
+            .hash_length(BLAKE2_SUM_LENGTH)
+            .hash(data);
+        let strong = &strong.as_bytes()[..self.strong_len];
+
+        candidates
+            .iter()
+            .find(|c| c.1.as_slice() == strong)
+            .map(|c| c.0)
+    }
+}
+
+fn read_u32(b: &[u8]) -> u32 {
+    u32::from_be_bytes([b[0], b[1], b[2], b[3]])
+}
+
+/// Part of the scanned input
+pub(crate) enum Chunk<'a> {
+    /// Data not contained in the signature
+    Literal(&'a [u8]),
+    /// Data matching a signature block
+    Match {
+        offset: u64,
+        block: usize,
+        len: usize,
+    },
+}
+
+/// Scans `reader` for blocks contained in `sig`.
+/// Every byte of the input is reported either as literal or as part of a matched block.
+pub(crate) fn scan<R: Read>(
+    sig: &Signature,
+    reader: &mut R,
+    on_chunk: &mut dyn FnMut(Chunk) -> io::Result<()>,
+) -> io::Result<()> {
+    let block_len = sig.block_len;
+    let chunk_len = std::cmp::max(block_len * 4, 1 << 16);
+
+    let mut buf: Vec<u8> = Vec::new();
+    let mut buf_offset: u64 = 0;
+    let mut pos: usize = 0;
+    let mut literal_start: usize = 0;
+    let mut eof = false;
+    let mut roll: Option<Rollsum> = None;
+
+    loop {
+        // Keep atleast one byte after the window, so it can be rotated in
+        if !eof && buf.len() - pos <= block_len {
+            if literal_start < pos {
+                on_chunk(Chunk::Literal(&buf[literal_start..pos]))?;
+            }
+            buf.drain(..pos);
+            buf_offset += pos as u64;
+            pos = 0;
+            literal_start = 0;
+
+            let mut chunk = vec![0u8; chunk_len];
+            while buf.len() <= block_len {
+                let read = reader.read(&mut chunk)?;
+                if read == 0 {
+                    eof = true;
+                    break;
+                }
+                buf.extend_from_slice(&chunk[..read]);
+            }
+        }
+
+        let avail = buf.len() - pos;
+        if avail == 0 {
+            break;
+        }
+        let window = std::cmp::min(block_len, avail);
+
+        let weak = match &roll {
+            Some(v) => v.digest(),
+            None => {
+                let v = Rollsum::new(&buf[pos..pos + window]);
+                let d = v.digest();
+                roll = Some(v);
+                d
+            }
+        };
+
+        if let Some(block) = sig.find(weak, &buf[pos..pos + window]) {
+            if literal_start < pos {
+                on_chunk(Chunk::Literal(&buf[literal_start..pos]))?;
+            }
+            on_chunk(Chunk::Match {
+                offset: buf_offset + pos as u64,
+                block,
+                len: window,
+            })?;
+            pos += window;
+            literal_start = pos;
+            roll = None;
+            continue;
+        }
+
+        if let Some(v) = roll.as_mut() {
+            if window == block_len && pos + block_len < buf.len() {
+                v.rotate(buf[pos], buf[pos + block_len]);
+            } else if eof {
+                v.rollout(buf[pos]);
+            } else {
+                roll = None;
+            }
+        }
+        pos += 1;
+    }
+
+    if literal_start < pos {
+        on_chunk(Chunk::Literal(&buf[literal_start..pos]))?;
+    }
+
+    Ok(())
+}
+
+/// Removes the temporary file of a rebuild on every error, after a successful `finish` it is already gone
+struct TmpFile<'a>(&'a Path);
+
+impl Drop for TmpFile<'_> {
+    fn drop(&mut self) {
+        std::fs::remove_file(self.0).unwrap_or_default();
+    }
+}
+
+/// Moves the rebuilt file to `filepath`, if it matches `hash`
+fn finish(tmppath: &Path, filepath: &Path, hash: &ContentHash) -> Result<(), DeltaError> {
+    if !hash.matches(tmppath)? {
+        return Err(DeltaError::HashMismatch);
+    }
+    std::fs::rename(tmppath, filepath)?;
+
+    Ok(())
+}
+
+fn fetch_signature(sig_url: &str) -> Result<Signature, DeltaError> {
+    let mut resp = reqwest::get(sig_url)?;
+    if !resp.status().is_success() {
+        return Err(DeltaError::SignatureNotFound);
+    }
+    let mut data: Vec<u8> = Vec::new();
+    resp.read_to_end(&mut data)?;
+
+    Signature::parse(&data)
+}
+
+/// Rebuilds the remote file at `url` from the local `base` file, by only downloading blocks missing in `base`.
+/// Returns the amount of downloaded bytes
+pub(crate) fn download_delta(
+    url: &str,
+    base: &Path,
+    filepath: &Path,
+    hash: &ContentHash,
+) -> Result<u64, DeltaError> {
+    let sig = fetch_signature(&(url.to_owned() + ".a3mo_delta"))?;
+    let block_len = sig.block_len;
+
+    // Remote block index -> offset inside base
+    let mut matches: HashMap<usize, u64> = HashMap::new();
+    let mut base_file = File::open(base)?;
+    scan(&sig, &mut base_file, &mut |chunk| {
+        if let Chunk::Match { offset, block, len } = chunk {
+            if len == block_len {
+                matches.entry(block).or_insert(offset);
+            }
+        }
+        Ok(())
+    })?;
+
+    let client = reqwest::Client::new();
+    let tmppath = store::append_suffix(filepath, ".delta");
+    let _tmp = TmpFile(&tmppath);
+    let mut out = File::create(&tmppath)?;
+    let mut block_buf = vec![0u8; block_len];
+    let mut downloaded: u64 = 0;
+
+    let mut i = 0;
+    while i < sig.block_count {
+        // The length of the last block is unknown, so it is always downloaded
+        if i + 1 < sig.block_count {
+            if let Some(offset) = matches.get(&i) {
+                base_file.seek(SeekFrom::Start(*offset))?;
+                base_file.read_exact(&mut block_buf)?;
+                out.write_all(&block_buf)?;
+                i += 1;
+                continue;
+            }
+        }
+
+        let mut j = i + 1;
+        while j + 1 < sig.block_count && !matches.contains_key(&j) {
+            j += 1;
+        }
+        if j + 1 == sig.block_count {
+            j = sig.block_count;
+        }
+
+        let range = if j == sig.block_count {
+            format!("bytes={}-", i * block_len)
+        } else {
+            format!("bytes={}-{}", i * block_len, j * block_len - 1)
+        };
+
+        let mut resp = client.get(url).header(RANGE, range).send()?;
+        if resp.status() != StatusCode::PARTIAL_CONTENT {
+            return Err(DeltaError::RangeNotSupported);
+        }
+        downloaded += io::copy(&mut resp, &mut out)?;
+        i = j;
+    }
+    out.flush()?;
+
+    drop(out);
+    finish(&tmppath, filepath, hash)?;
+
+    Ok(downloaded)
+}
+
+const PATCH_MAGIC: u32 = 0x4133_4d50;
+const PATCH_OP_END: u8 = 0;
+const PATCH_OP_COPY: u8 = 1;
+const PATCH_OP_LITERAL: u8 = 2;
+
+fn write_copy<W: Write>(out: &mut W, offset: u64, len: u64) -> io::Result<()> {
+    out.write_all(&[PATCH_OP_COPY])?;
+    out.write_all(&offset.to_be_bytes())?;
+    out.write_all(&len.to_be_bytes())
+}
+
+/// Writes a patch, which rebuilds `new` from the file `sig` was generated from.
+/// Returns the size of the patch in bytes
+pub(crate) fn generate_patch<R: Read, W: Write>(
+    sig: &Signature,
+    new: &mut R,
+    out: &mut W,
+) -> io::Result<u64> {
+    out.write_all(&PATCH_MAGIC.to_be_bytes())?;
+    let mut written: u64 = 4;
+
+    // Consecutive copies are merged into one operation
+    let mut pending_copy: Option<(u64, u64)> = None;
+
+    scan(sig, new, &mut |chunk| {
+        match chunk {
+            Chunk::Literal(data) => {
+                if let Some((offset, len)) = pending_copy.take() {
+                    write_copy(out, offset, len)?;
+                    written += 17;
+                }
+                out.write_all(&[PATCH_OP_LITERAL])?;
+                out.write_all(&(data.len() as u64).to_be_bytes())?;
+                out.write_all(data)?;
+                written += 9 + data.len() as u64;
+            }
+            Chunk::Match { block, len, .. } => {
+                let offset = (block * sig.block_len) as u64;
+                pending_copy = match pending_copy.take() {
+                    Some((p_offset, p_len)) if p_offset + p_len == offset => {
+                        Some((p_offset, p_len + len as u64))
+                    }
+                    Some((p_offset, p_len)) => {
+                        write_copy(out, p_offset, p_len)?;
+                        written += 17;
+                        Some((offset, len as u64))
+                    }
+                    None => Some((offset, len as u64)),
+                };
+            }
+        }
+        Ok(())
+    })?;
+
+    if let Some((offset, len)) = pending_copy {
+        write_copy(out, offset, len)?;
+        written += 17;
+    }
+    // Marks the patch as complete, a truncated patch is rejected
+    out.write_all(&[PATCH_OP_END])?;
+    written += 1;
+    out.flush()?;
+
+    Ok(written)
+}
+
+fn read_u64<R: Read>(r: &mut R) -> io::Result<u64> {
+    let mut b = [0u8; 8];
+    r.read_exact(&mut b)?;
+    Ok(u64::from_be_bytes(b))
+}
+
+/// Rebuilds a file from `base` and the patch read from `patch`
+pub(crate) fn apply_patch<B: Read + Seek, R: Read, W: Write>(
+    base: &mut B,
+    patch: &mut R,
+    out: &mut W,
+) -> Result<(), DeltaError> {
+    let mut magic = [0u8; 4];
+    patch.read_exact(&mut magic)?;
+    if u32::from_be_bytes(magic) != PATCH_MAGIC {
+        return Err(DeltaError::InvalidPatch);
+    }
+
+    let mut op = [0u8; 1];
+    loop {
+        if patch.read(&mut op)? == 0 {
+            return Err(DeltaError::InvalidPatch);
+        }
+        match op[0] {
+            PATCH_OP_END => break,
+            PATCH_OP_COPY => {
+                let offset = read_u64(patch)?;
+                let len = read_u64(patch)?;
+                base.seek(SeekFrom::Start(offset))?;
+                if io::copy(&mut (&mut *base).take(len), out)? != len {
+                    return Err(DeltaError::InvalidPatch);
+                }
+            }
+            PATCH_OP_LITERAL => {
+                let len = read_u64(patch)?;
+                if io::copy(&mut (&mut *patch).take(len), out)? != len {
+                    return Err(DeltaError::InvalidPatch);
+                }
+            }
+            _ => return Err(DeltaError::InvalidPatch),
+        }
+    }
+    out.flush()?;
+
+    Ok(())
+}
+
+/// Rebuilds a file from the local `base` file and the prebuilt patch at `patch_url`.
+/// Returns the amount of downloaded bytes
+pub(crate) fn download_patch(
+    patch_url: &str,
+    base: &Path,
+    filepath: &Path,
+    hash: &ContentHash,
+) -> Result<u64, DeltaError> {
+    let resp = reqwest::get(patch_url)?;
+    if !resp.status().is_success() {
+        return Err(DeltaError::PatchNotFound);
+    }
+
+    let mut counted = CountingReader {
+        inner: resp,
+        count: 0,
+    };
+    let mut base_file = File::open(base)?;
+    let tmppath = store::append_suffix(filepath, ".delta");
+    let _tmp = TmpFile(&tmppath);
+    let mut out = File::create(&tmppath)?;
+    apply_patch(&mut base_file, &mut counted, &mut out)?;
+
+    drop(out);
+    finish(&tmppath, filepath, hash)?;
+
+    Ok(counted.count)
+}
+
+struct CountingReader<R: Read> {
+    inner: R,
+    count: u64,
+}
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.count += read as u64;
+        Ok(read)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use delta_patch::mksum::SignatureOptions;
+
+    const BLOCK_LEN: usize = 2048;
+
+    /// Deterministic pseudo random test data
+    fn data(len: usize, seed: u32) -> Vec<u8> {
+        let mut state = seed;
+        (0..len)
+            .map(|_| {
+                state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                (state >> 16) as u8
+            })
+            .collect()
+    }
+
+    fn signature(base: &[u8]) -> Signature {
+        let mut sig: Vec<u8> = Vec::new();
+        delta_patch::mksum::generate_signature(
+            &mut &base[..],
+            &SignatureOptions::default(),
+            &mut sig,
+        )
+        .unwrap();
+        Signature::parse(&sig).unwrap()
+    }
+
+    /// Rebuilds `target` from the blocks of `base` found by scan and the literal data.
+    /// Returns the rebuilt data and the amount of bytes taken from `base`
+    fn rebuild(base: &[u8], target: &[u8]) -> (Vec<u8>, usize) {
+        let sig = signature(base);
+        assert_eq!(sig.block_len, BLOCK_LEN);
+
+        let mut out: Vec<u8> = Vec::new();
+        let mut matched = 0;
+        scan(&sig, &mut &target[..], &mut |chunk| {
+            match chunk {
+                Chunk::Literal(v) => out.extend_from_slice(v),
+                Chunk::Match { block, len, .. } => {
+                    let start = block * sig.block_len;
+                    out.extend_from_slice(&base[start..start + len]);
+                    matched += len;
+                }
+            }
+            Ok(())
+        })
+        .unwrap();
+
+        (out, matched)
+    }
+
+    #[test]
+    fn scan_identical() {
+        for len in &[1, 100, BLOCK_LEN, 3 * BLOCK_LEN, 3 * BLOCK_LEN + 500] {
+            let base = data(*len, 1);
+            let (out, matched) = rebuild(&base, &base);
+            assert_eq!(out, base);
+            assert_eq!(matched, *len);
+        }
+    }
+
+    #[test]
+    fn scan_modified() {
+        let base = data(5 * BLOCK_LEN + 123, 2);
+        let mut target = base.clone();
+        target[2 * BLOCK_LEN + 10] ^= 0xff;
+        target.splice(4 * BLOCK_LEN..4 * BLOCK_LEN, data(77, 3));
+
+        let (out, matched) = rebuild(&base, &target);
+        assert_eq!(out, target);
+        // Only the changed block is not found, including the short last block
+        assert_eq!(matched, 4 * BLOCK_LEN + 123);
+    }
+
+    #[test]
+    fn scan_shifted() {
+        let base = data(3 * BLOCK_LEN + 500, 4);
+        let mut target = data(3, 5);
+        target.extend_from_slice(&base);
+
+        let (out, matched) = rebuild(&base, &target);
+        assert_eq!(out, target);
+        assert_eq!(matched, base.len());
+    }
+
+    fn patch(base: &[u8], target: &[u8]) -> Vec<u8> {
+        let mut patch: Vec<u8> = Vec::new();
+        let size = generate_patch(&signature(base), &mut &target[..], &mut patch).unwrap();
+        assert_eq!(size, patch.len() as u64);
+        patch
+    }
+
+    fn apply(base: &[u8], patch: &[u8]) -> Result<Vec<u8>, DeltaError> {
+        let mut out: Vec<u8> = Vec::new();
+        apply_patch(&mut io::Cursor::new(base), &mut &patch[..], &mut out)?;
+        Ok(out)
+    }
+
+    #[test]
+    fn patch_round_trip() {
+        let base = data(6 * BLOCK_LEN + 700, 8);
+        let mut target = base.clone();
+        target[BLOCK_LEN + 5] ^= 0xff;
+        target.splice(3 * BLOCK_LEN..3 * BLOCK_LEN, data(300, 9));
+        target.truncate(5 * BLOCK_LEN);
+
+        let p = patch(&base, &target);
+        assert!(p.len() < target.len());
+        assert_eq!(apply(&base, &p).unwrap(), target);
+
+        for (b, t) in &[(data(100, 10), data(120, 11)), (data(0, 0), data(50, 12))] {
+            assert_eq!(apply(b, &patch(b, t)).unwrap(), *t);
+        }
+        assert_eq!(apply(&base, &patch(&base, &[])).unwrap(), Vec::<u8>::new());
+    }
+
+    #[test]
+    fn patch_truncated() {
+        let base = data(4 * BLOCK_LEN, 13);
+        let mut target = data(100, 14);
+        target.extend_from_slice(&base);
+        let p = patch(&base, &target);
+
+        // Every truncation is rejected, including the ones at operation boundaries
+        for len in 0..p.len() {
+            assert!(apply(&base, &p[..len]).is_err(), "truncated at {}", len);
+        }
+    }
+
+    #[test]
+    fn patch_corrupt() {
+        let base = data(2 * BLOCK_LEN, 15);
+        let p = patch(&base, &base);
+
+        let mut bad_magic = p.clone();
+        bad_magic[0] ^= 0xff;
+        assert!(matches!(
+            apply(&base, &bad_magic),
+            Err(DeltaError::InvalidPatch)
+        ));
+
+        let mut bad_op = p.clone();
+        bad_op[4] = 0x7f;
+        assert!(matches!(
+            apply(&base, &bad_op),
+            Err(DeltaError::InvalidPatch)
+        ));
+
+        // Copy beyond the end of the base
+        let mut bad_copy = p;
+        bad_copy[5..13].copy_from_slice(&(BLOCK_LEN as u64 * 10).to_be_bytes());
+        assert!(matches!(
+            apply(&base, &bad_copy),
+            Err(DeltaError::InvalidPatch)
+        ));
+    }
+
+    #[test]
+    fn scan_short() {
+        let base = data(100, 6);
+        let target = data(150, 7);
+
+        let (out, matched) = rebuild(&base, &target);
+        assert_eq!(out, target);
+        assert_eq!(matched, 0);
+
+        let (out, _) = rebuild(&base, &[]);
+        assert!(out.is_empty());
+    }
+}
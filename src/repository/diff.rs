@@ -0,0 +1,420 @@
+use crate::repository::manifest;
+use crate::repository::manifest::{Manifest, ManifestEntry};
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+/// Change of a single file or folder between two manifests
+#[derive(Debug, Clone, PartialEq)]
+pub enum Change {
+    /// `size` is the size of the file or of all files inside the folder, which are not moved
+    Added {
+        path: String,
+        is_folder: bool,
+        size: u64,
+    },
+    Removed {
+        path: String,
+        is_folder: bool,
+        size: u64,
+    },
+    /// File with new content at the same path
+    Modified {
+        path: String,
+        old_size: u64,
+        new_size: u64,
+    },
+    /// File or folder with unchanged content at another path
+    Moved {
+        from: String,
+        to: String,
+        is_folder: bool,
+        size: u64,
+    },
+}
+
+/// Identity of a file content, the strong hash if known
+fn content_key(fse: &ManifestEntry) -> String {
+    if fse.blake2b.is_empty() {
+        fse.hash.to_string()
+    } else {
+        fse.blake2b.clone()
+    }
+}
+
+fn same_content(a: &ManifestEntry, b: &ManifestEntry) -> bool {
+    if a.blake2b.is_empty() || b.blake2b.is_empty() {
+        a.hash == b.hash
+    } else {
+        a.blake2b == b.blake2b
+    }
+}
+
+/// Entries inside the folder `path`
+fn children<'a>(
+    entries: &'a BTreeMap<String, ManifestEntry>,
+    path: &str,
+) -> impl Iterator<Item = (&'a String, &'a ManifestEntry)> {
+    let prefix = String::from(path) + "/";
+    entries
+        .range(prefix.clone()..)
+        .take_while(move |(k, _)| k.starts_with(&prefix))
+}
+
+fn folder_size(entries: &BTreeMap<String, ManifestEntry>, path: &str) -> u64 {
+    children(entries, path).map(|(_, fse)| fse.size).sum()
+}
+
+/// Content of a folder, used to find moved folders. Empty folders have no content.
+fn folder_content(entries: &BTreeMap<String, ManifestEntry>, path: &str) -> Vec<(String, String)> {
+    children(entries, path)
+        .filter(|(_, fse)| !fse.is_folder)
+        .map(|(k, fse)| (String::from(&k[path.len()..]), content_key(fse)))
+        .collect()
+}
+
+/// Checks if any parent folder of `path` is one of `folders`
+fn is_inside(path: &str, folders: &HashSet<String>) -> bool {
+    let mut parent = manifest::parent_path(path);
+    while !parent.is_empty() {
+        if folders.contains(parent) {
+            return true;
+        }
+        parent = manifest::parent_path(parent);
+    }
+    false
+}
+
+/// Lists the changes from `old` to `new`.
+/// Files and folders, which only changed their path, are reported as moved instead of removed and added.
+/// Entries inside moved, removed or added folders are not reported separately.
+/// Changes are grouped by kind: moved, removed, added, modified.
+/// * `old` : Manifest of the current state, e.g. the local one
+/// * `new` : Manifest of the target state, e.g. the remote one
+pub fn diff(old: &Manifest, new: &Manifest) -> Vec<Change> {
+    let old_entries = &old.entries;
+    let new_entries = &new.entries;
+
+    let mut changes: Vec<Change> = Vec::new();
+
+    let removed: Vec<(&String, &ManifestEntry)> = old_entries
+        .iter()
+        .filter(|(k, _)| !new_entries.contains_key(*k))
+        .collect();
+    let added: Vec<(&String, &ManifestEntry)> = new_entries
+        .iter()
+        .filter(|(k, _)| !old_entries.contains_key(*k))
+        .collect();
+
+    //Folders moved as a whole, the outermost one is matched first
+    let mut moved_from: HashSet<String> = HashSet::new();
+    let mut moved_to: HashSet<String> = HashSet::new();
+    let mut added_folders: HashMap<Vec<(String, String)>, Vec<&String>> = HashMap::new();
+    for (k, fse) in &added {
+        if fse.is_folder {
+            let content = folder_content(new_entries, k);
+            if !content.is_empty() {
+                added_folders.entry(content).or_default().push(k);
+            }
+        }
+    }
+    for (k, fse) in &removed {
+        if !fse.is_folder || is_inside(k, &moved_from) {
+            continue;
+        }
+        let content = folder_content(old_entries, k);
+        let target = match added_folders.get_mut(&content) {
+            Some(v) => v
+                .iter()
+                .position(|t| !is_inside(t, &moved_to))
+                .map(|i| v.remove(i)),
+            None => None,
+        };
+        if let Some(to) = target {
+            changes.push(Change::Moved {
+                from: String::clone(k),
+                to: String::clone(to),
+                is_folder: true,
+                size: folder_size(old_entries, k),
+            });
+            moved_from.insert(String::clone(k));
+            moved_to.insert(String::clone(to));
+        }
+    }
+
+    //Single files, matched by content
+    let mut added_files: HashMap<String, Vec<&String>> = HashMap::new();
+    for (k, fse) in &added {
+        if !fse.is_folder && !is_inside(k, &moved_to) {
+            added_files.entry(content_key(fse)).or_default().push(k);
+        }
+    }
+    let mut moved_files: HashSet<String> = HashSet::new();
+    let mut removed_left: Vec<(&String, &ManifestEntry)> = Vec::new();
+    for (k, fse) in &removed {
+        if is_inside(k, &moved_from) || moved_from.contains(*k) {
+            continue;
+        }
+        if !fse.is_folder {
+            if let Some(to) = added_files.get_mut(&content_key(fse)).and_then(|v| v.pop()) {
+                changes.push(Change::Moved {
+                    from: String::clone(k),
+                    to: String::clone(to),
+                    is_folder: false,
+                    size: fse.size,
+                });
+                moved_files.insert(String::clone(k));
+                moved_to.insert(String::clone(to));
+                continue;
+            }
+        }
+        removed_left.push((k, fse));
+    }
+
+    //Remaining removals, contents of removed folders are covered by the folder.
+    //Files and folders moved out of a removed folder do not count to its size.
+    let removed_folders: HashSet<String> = removed_left
+        .iter()
+        .filter(|(_, fse)| fse.is_folder)
+        .map(|(k, _)| String::clone(k))
+        .collect();
+    for (k, fse) in removed_left {
+        if is_inside(k, &removed_folders) {
+            continue;
+        }
+        changes.push(Change::Removed {
+            path: String::clone(k),
+            is_folder: fse.is_folder,
+            size: if fse.is_folder {
+                children(old_entries, k)
+                    .filter(|(c, _)| !moved_files.contains(*c) && !is_inside(c, &moved_from))
+                    .map(|(_, c)| c.size)
+                    .sum()
+            } else {
+                fse.size
+            },
+        });
+    }
+
+    //Files and folders moved into an added folder do not count to its size
+    let added_left: Vec<(&String, &ManifestEntry)> = added
+        .into_iter()
+        .filter(|(k, _)| !moved_to.contains(*k) && !is_inside(k, &moved_to))
+        .collect();
+    let added_folders: HashSet<String> = added_left
+        .iter()
+        .filter(|(_, fse)| fse.is_folder)
+        .map(|(k, _)| String::clone(k))
+        .collect();
+    for (k, fse) in added_left {
+        if is_inside(k, &added_folders) {
+            continue;
+        }
+        changes.push(Change::Added {
+            path: String::clone(k),
+            is_folder: fse.is_folder,
+            size: if fse.is_folder {
+                children(new_entries, k)
+                    .filter(|(c, _)| !moved_to.contains(*c) && !is_inside(c, &moved_to))
+                    .map(|(_, c)| c.size)
+                    .sum()
+            } else {
+                fse.size
+            },
+        });
+    }
+
+    for (k, fse) in new_entries {
+        if let Some(o) = old_entries.get(k) {
+            if fse.is_folder != o.is_folder {
+                changes.push(Change::Removed {
+                    path: String::clone(k),
+                    is_folder: o.is_folder,
+                    size: o.size,
+                });
+                changes.push(Change::Added {
+                    path: String::clone(k),
+                    is_folder: fse.is_folder,
+                    size: fse.size,
+                });
+            } else if !fse.is_folder && !same_content(o, fse) {
+                changes.push(Change::Modified {
+                    path: String::clone(k),
+                    old_size: o.size,
+                    new_size: fse.size,
+                });
+            }
+        }
+    }
+
+    changes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn folder() -> ManifestEntry {
+        ManifestEntry {
+            is_folder: true,
+            hash: 0,
+            blake2b: String::new(),
+            size: 0,
+            mtime: 0,
+        }
+    }
+
+    fn file(content: u64, size: u64) -> ManifestEntry {
+        ManifestEntry {
+            is_folder: false,
+            hash: content,
+            blake2b: format!("{:032x}", content),
+            size,
+            mtime: 0,
+        }
+    }
+
+    fn manifest(entries: Vec<(&str, ManifestEntry)>) -> Manifest {
+        let entries = entries
+            .into_iter()
+            .map(|(k, v)| (String::from(k), v))
+            .collect();
+        Manifest::new("repo", 0, entries).unwrap()
+    }
+
+    #[test]
+    fn added() {
+        let old = manifest(vec![("@ace", folder())]);
+        let new = manifest(vec![
+            ("@ace", folder()),
+            ("@ace/a.pbo", file(1, 10)),
+            ("@cba", folder()),
+            ("@cba/b.pbo", file(2, 20)),
+            ("@cba/c.pbo", file(3, 30)),
+        ]);
+        assert_eq!(
+            diff(&old, &new),
+            vec![
+                Change::Added {
+                    path: String::from("@ace/a.pbo"),
+                    is_folder: false,
+                    size: 10
+                },
+                Change::Added {
+                    path: String::from("@cba"),
+                    is_folder: true,
+                    size: 50
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn removed() {
+        let old = manifest(vec![
+            ("@ace", folder()),
+            ("@ace/a.pbo", file(1, 10)),
+            ("@cba", folder()),
+            ("@cba/b.pbo", file(2, 20)),
+        ]);
+        let new = manifest(vec![("@ace", folder())]);
+        assert_eq!(
+            diff(&old, &new),
+            vec![
+                Change::Removed {
+                    path: String::from("@ace/a.pbo"),
+                    is_folder: false,
+                    size: 10
+                },
+                Change::Removed {
+                    path: String::from("@cba"),
+                    is_folder: true,
+                    size: 20
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn modified() {
+        let old = manifest(vec![("a.pbo", file(1, 10)), ("b.pbo", file(2, 20))]);
+        let new = manifest(vec![("a.pbo", file(3, 15)), ("b.pbo", file(2, 20))]);
+        assert_eq!(
+            diff(&old, &new),
+            vec![Change::Modified {
+                path: String::from("a.pbo"),
+                old_size: 10,
+                new_size: 15
+            }]
+        );
+    }
+
+    #[test]
+    fn moved_file() {
+        let old = manifest(vec![("@ace", folder()), ("@ace/a.pbo", file(1, 10))]);
+        let new = manifest(vec![("@ace", folder()), ("@ace/b.pbo", file(1, 10))]);
+        assert_eq!(
+            diff(&old, &new),
+            vec![Change::Moved {
+                from: String::from("@ace/a.pbo"),
+                to: String::from("@ace/b.pbo"),
+                is_folder: false,
+                size: 10
+            }]
+        );
+    }
+
+    #[test]
+    fn moved_folder() {
+        let old = manifest(vec![
+            ("@ace", folder()),
+            ("@ace/addons", folder()),
+            ("@ace/addons/a.pbo", file(1, 10)),
+            ("@ace/addons/b.pbo", file(2, 20)),
+        ]);
+        let new = manifest(vec![
+            ("@ACE3", folder()),
+            ("@ACE3/addons", folder()),
+            ("@ACE3/addons/a.pbo", file(1, 10)),
+            ("@ACE3/addons/b.pbo", file(2, 20)),
+        ]);
+        assert_eq!(
+            diff(&old, &new),
+            vec![Change::Moved {
+                from: String::from("@ace"),
+                to: String::from("@ACE3"),
+                is_folder: true,
+                size: 30
+            }]
+        );
+    }
+
+    #[test]
+    fn moved_out_of_removed_folder() {
+        let old = manifest(vec![
+            ("@ace", folder()),
+            ("@ace/a.pbo", file(1, 10)),
+            ("@ace/b.pbo", file(2, 20)),
+        ]);
+        let new = manifest(vec![("@cba", folder()), ("@cba/a.pbo", file(1, 10))]);
+        assert_eq!(
+            diff(&old, &new),
+            vec![
+                Change::Moved {
+                    from: String::from("@ace/a.pbo"),
+                    to: String::from("@cba/a.pbo"),
+                    is_folder: false,
+                    size: 10
+                },
+                Change::Removed {
+                    path: String::from("@ace"),
+                    is_folder: true,
+                    size: 20
+                },
+                Change::Added {
+                    path: String::from("@cba"),
+                    is_folder: true,
+                    size: 0
+                },
+            ]
+        );
+    }
+}
@@ -0,0 +1,80 @@
+extern crate custom_error;
+use crate::repository::store;
+use crate::sql::sqlite;
+use custom_error::custom_error;
+use log::{debug, info};
+use std::path::Path;
+use std::time::SystemTime;
+
+extern crate rusqlite;
+
+custom_error! {pub GcError
+    FolderNotFound = "Folder not found!",
+    SQLError{source: rusqlite::Error} = "SQL Error",
+    SystemTimeErr{source: std::time::SystemTimeError} = "System Time Error",
+    IOError{source: std::io::Error} = "IO Error"
+}
+
+/// Blobs and unfinished downloads removed by a garbage collection
+#[derive(Debug, Default)]
+pub struct GcReport {
+    pub removed: Vec<String>,
+    pub freed_bytes: u64,
+}
+
+/// Deletes all blobs inside the repository folder, which are no longer referenced,
+/// together with unfinished downloads (.part) and rebuilds (.delta) of them.
+/// Files of every repository sharing the folder are kept.
+/// * `name` : Repository name (as given to the clone command)
+/// * `dry_run` : Only report what would be deleted
+pub fn gc(name: &str, dry_run: bool) -> Result<GcReport, GcError> {
+    info!(target: "a3mo::gc", "Collecting garbage of repository {:?}", &name);
+    let start = SystemTime::now();
+
+    let mut conn = sqlite::get_conn()?;
+    let repository = sqlite::get_repository(name, &mut conn)?;
+
+    if !Path::exists(repository.path.as_ref()) {
+        return Err(GcError::FolderNotFound);
+    };
+
+    let referenced = store::referenced_hashes(&repository.path, &mut conn)?;
+
+    let mut report = GcReport::default();
+    for (blob, size) in store::list_blobs(&repository.path)? {
+        if referenced.contains(&blob) {
+            continue;
+        }
+
+        debug!(target: "a3mo::gc", "Remove {:?} ({:?} byte)", &blob, &size);
+        if !dry_run {
+            std::fs::remove_file(store::blob_path(&repository.path, &blob))?;
+        }
+        report.freed_bytes += size;
+        report.removed.push(blob);
+    }
+
+    //Unfinished downloads of blobs, which are no longer needed
+    for (file, blob, size) in store::list_leftovers(&repository.path)? {
+        if referenced.contains(&blob) {
+            continue;
+        }
+
+        debug!(target: "a3mo::gc", "Remove {:?} ({:?} byte)", &file, &size);
+        if !dry_run {
+            std::fs::remove_file(store::blob_path(&repository.path, &file))?;
+        }
+        report.freed_bytes += size;
+        report.removed.push(file);
+    }
+
+    info!(
+        target: "a3mo::gc",
+        "Finished collecting garbage. {:?} blobs, {:?} byte in {:?} sec",
+        report.removed.len(),
+        report.freed_bytes,
+        start.elapsed()?
+    );
+
+    Ok(report)
+}
@@ -0,0 +1,83 @@
+use crate::repository::manifest::ManifestEntry;
+use crate::repository::store;
+use easy_xxhash64::XxHash64;
+use std::fs::File;
+use std::hash::Hasher;
+use std::io;
+use std::io::Read;
+use std::path::Path;
+
+/// Length of the BLAKE2b digest in byte (hex encoded twice as long)
+const BLAKE2B_LENGTH: usize = 32;
+
+/// Passes the content of the file at `path` to `update` in chunks, instead of reading it at once
+fn read_chunks(path: &Path, update: &mut dyn FnMut(&[u8])) -> io::Result<()> {
+    let mut file = File::open(path)?;
+
+    let mut buf = vec![0u8; 1 << 16];
+    loop {
+        let read = file.read(&mut buf)?;
+        if read == 0 {
+            return Ok(());
+        }
+        update(&buf[..read]);
+    }
+}
+
+/// Computes the xxHash64 of the file at `path`
+pub(crate) fn xxhash_path(path: &Path) -> io::Result<u64> {
+    let mut hasher = XxHash64::with_seed(0);
+    read_chunks(path, &mut |chunk| hasher.write(chunk))?;
+
+    Ok(hasher.finish())
+}
+
+/// Computes the hex encoded BLAKE2b digest of the file at `path`
+pub(crate) fn blake2b_path(path: &Path) -> io::Result<String> {
+    let mut state = blake2b_simd::Params::new()
+        .hash_length(BLAKE2B_LENGTH)
+        .to_state();
+    read_chunks(path, &mut |chunk| {
+        state.update(chunk);
+    })?;
+
+    Ok(state.finalize().to_hex().to_string())
+}
+
+/// Expected content of a file
+#[derive(Debug, Clone)]
+pub(crate) struct ContentHash {
+    pub xx_hash: u64,
+    /// Empty for manifests built before a3mo_lib recorded BLAKE2b digests
+    pub blake2b: String,
+}
+
+impl ContentHash {
+    pub(crate) fn new(xx_hash: u64, blake2b: &str) -> ContentHash {
+        ContentHash {
+            xx_hash,
+            blake2b: String::from(blake2b),
+        }
+    }
+
+    pub(crate) fn of(fse: &ManifestEntry) -> ContentHash {
+        ContentHash::new(fse.hash, &fse.blake2b)
+    }
+
+    /// Name of the blob inside the content store
+    pub(crate) fn blob_name(&self) -> String {
+        store::blob_name(&self.xx_hash.to_string(), &self.blake2b)
+    }
+
+    /// Checks the file at `path`, the xxHash64 is compared first to skip the BLAKE2b of mismatching files
+    pub(crate) fn matches(&self, path: &Path) -> io::Result<bool> {
+        if xxhash_path(path)? != self.xx_hash {
+            return Ok(false);
+        }
+        if self.blake2b.is_empty() {
+            return Ok(true);
+        }
+
+        Ok(blake2b_path(path)? == self.blake2b)
+    }
+}
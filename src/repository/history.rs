@@ -0,0 +1,166 @@
+extern crate custom_error;
+use crate::repository::manifest::Manifest;
+use crate::sql::sqlite;
+use custom_error::custom_error;
+use reqwest;
+use serde::{Deserialize, Serialize};
+use serde_json;
+use std::path::Path;
+
+extern crate rusqlite;
+
+custom_error! {pub HistoryError
+    FolderNotFound = "Folder not found!",
+    SQLError{source: rusqlite::Error} = "SQL Error",
+    SerdeError{source: serde_json::Error} = "Serde Error",
+    IOError{source: std::io::Error} = "IO Error",
+    RequestError{source: reqwest::Error} = "Request Error",
+    RevisionNotFound = "Revision not found"
+}
+
+/// Published revision of a repository
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RevisionInfo {
+    pub revision: u64,
+    /// Unix timestamp (seconds) of the build
+    pub build_time: u64,
+    /// Amount of files
+    pub files: usize,
+    /// Size of all files in byte
+    pub size: u64,
+    /// Names given to the revision, every tag belongs to one revision only
+    #[serde(default)]
+    pub tags: Vec<String>,
+}
+
+/// Content of the revisions.json, sorted by revision
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct RevisionIndex {
+    pub revisions: Vec<RevisionInfo>,
+}
+
+impl RevisionIndex {
+    pub fn latest(&self) -> Option<&RevisionInfo> {
+        self.revisions.last()
+    }
+
+    pub fn find(&self, revision: u64) -> Option<&RevisionInfo> {
+        self.revisions.iter().find(|r| r.revision == revision)
+    }
+
+    pub fn find_tag(&self, tag: &str) -> Option<&RevisionInfo> {
+        self.revisions
+            .iter()
+            .find(|r| r.tags.iter().any(|t| t == tag))
+    }
+}
+
+/// Name of the manifest of `revision` inside the revisions folder, stored gzip compressed
+pub(crate) fn revision_name(revision: u64) -> String {
+    format!("{}.json", revision)
+}
+
+/// Reads the revisions.json inside the sync folder, an empty index if there is none yet
+pub(crate) fn read_index(sync_folder_path: &Path) -> Result<RevisionIndex, HistoryError> {
+    let index_path = sync_folder_path.join("revisions.json");
+    match std::fs::read_to_string(&index_path) {
+        Ok(v) => Ok(serde_json::from_str(&v)?),
+        Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => Ok(RevisionIndex::default()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Stores the manifest of a build as revision and adds it to the revisions.json.
+/// * `sync_folder_path` : .a3mo folder of the repository
+/// * `manifest` : Manifest of the build
+/// * `gz_path` : Compressed sync.json of the build
+/// * `signature` : Signature of the sync.json, if signed
+pub(crate) fn record(
+    sync_folder_path: &Path,
+    manifest: &Manifest,
+    gz_path: &Path,
+    signature: Option<&str>,
+) -> Result<(), HistoryError> {
+    let revisions_path = sync_folder_path.join("revisions");
+    std::fs::create_dir_all(&revisions_path)?;
+
+    let manifest_name = revision_name(manifest.revision);
+    std::fs::copy(gz_path, revisions_path.join(manifest_name.clone() + ".gz"))?;
+    let sig_path = revisions_path.join(manifest_name + ".sig");
+    match signature {
+        Some(v) => std::fs::write(sig_path, v)?,
+        None => std::fs::remove_file(sig_path).unwrap_or_default(),
+    }
+
+    let files = manifest.entries.values().filter(|e| !e.is_folder);
+    let info = RevisionInfo {
+        revision: manifest.revision,
+        build_time: manifest.build_time,
+        files: files.clone().count(),
+        size: files.map(|e| e.size).sum(),
+        tags: Vec::new(),
+    };
+
+    let mut index = read_index(sync_folder_path)?;
+    index.revisions.retain(|r| r.revision != info.revision);
+    index.revisions.push(info);
+    index.revisions.sort_by_key(|r| r.revision);
+
+    write_index(sync_folder_path, &index)
+}
+
+fn write_index(sync_folder_path: &Path, index: &RevisionIndex) -> Result<(), HistoryError> {
+    //Replace the index at once, clients might download it right now
+    let index_path = sync_folder_path.join("revisions.json");
+    let tmp_index_path = sync_folder_path.join("revisions.json.tmp");
+    std::fs::write(&tmp_index_path, serde_json::to_string_pretty(index)?)?;
+    std::fs::rename(&tmp_index_path, &index_path)?;
+
+    Ok(())
+}
+
+/// Downloads the revisions.json of the a3mo folder at `url`
+pub(crate) fn fetch_index(url: &str) -> Result<RevisionIndex, HistoryError> {
+    let index_url = url.to_owned() + "/revisions.json";
+    let mut resp = reqwest::get(&index_url)?.error_for_status()?;
+
+    Ok(serde_json::from_str(&resp.text()?)?)
+}
+
+/// Lists all revisions published by build
+/// * `name` : Repository name (Has to be created using new command)
+pub fn history(name: &str) -> Result<Vec<RevisionInfo>, HistoryError> {
+    let mut conn = sqlite::get_conn()?;
+    let repo = sqlite::get_repository(name, &mut conn)?;
+
+    if !Path::new(&repo.path).exists() {
+        return Err(HistoryError::FolderNotFound);
+    }
+
+    Ok(read_index(&Path::new(&repo.path).join(".a3mo"))?.revisions)
+}
+
+/// Names a published revision, e.g. "stable" or "operation-2019-10-05".
+/// The tag is removed from the revision it belonged to before.
+/// * `name` : Repository name (Has to be created using new command)
+/// * `revision` : Revision to tag
+/// * `tag` : Name of the revision
+pub fn tag(name: &str, revision: u64, tag: &str) -> Result<(), HistoryError> {
+    let mut conn = sqlite::get_conn()?;
+    let repo = sqlite::get_repository(name, &mut conn)?;
+    let sync_folder_path = Path::new(&repo.path).join(".a3mo");
+
+    let mut index = read_index(&sync_folder_path)?;
+    if index.find(revision).is_none() {
+        return Err(HistoryError::RevisionNotFound);
+    }
+
+    for r in &mut index.revisions {
+        r.tags.retain(|t| t != tag);
+        if r.revision == revision {
+            r.tags.push(String::from(tag));
+        }
+    }
+
+    write_index(&sync_folder_path, &index)
+}
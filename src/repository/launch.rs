@@ -0,0 +1,137 @@
+use std::ffi::OsStr;
+use std::path::{Component, Path, PathBuf};
+use std::process::Command;
+
+/// Way of starting the ArmA3 executable on the current platform
+pub trait LaunchBackend {
+    /// Command starting `executable`, arguments are added by run
+    fn command(&self, executable: &str) -> Command;
+
+    /// `path` as seen by the game, used for the -mod list
+    fn game_path(&self, path: &Path) -> String;
+}
+
+/// Starts the executable directly (Windows)
+pub struct Native;
+
+impl LaunchBackend for Native {
+    fn command(&self, executable: &str) -> Command {
+        Command::new(executable)
+    }
+
+    fn game_path(&self, path: &Path) -> String {
+        path.to_string_lossy().into_owned()
+    }
+}
+
+/// Starts the executable through Steam Proton (Linux)
+pub struct Proton {
+    /// The proton script inside the Proton installation, e.g. ~/.steam/steam/steamapps/common/Proton 5.0/proton
+    pub proton_path: PathBuf,
+    /// Prefix of the game, e.g. ~/.steam/steam/steamapps/compatdata/107410
+    pub compat_data_path: PathBuf,
+    /// Steam installation, required by newer Proton versions
+    pub steam_path: Option<PathBuf>,
+}
+
+impl LaunchBackend for Proton {
+    fn command(&self, executable: &str) -> Command {
+        let mut command = Command::new(&self.proton_path);
+        command
+            .arg("run")
+            .arg(executable)
+            .env("STEAM_COMPAT_DATA_PATH", &self.compat_data_path);
+        if let Some(v) = &self.steam_path {
+            command.env("STEAM_COMPAT_CLIENT_INSTALL_PATH", v);
+        }
+        command
+    }
+
+    fn game_path(&self, path: &Path) -> String {
+        wine_path(path)
+    }
+}
+
+/// Starts the executable through Wine (Linux)
+pub struct Wine {
+    /// Wine executable, e.g. "wine" to use the one found in PATH
+    pub wine_path: PathBuf,
+    /// Wine prefix, the default prefix (~/.wine) if none
+    pub prefix: Option<PathBuf>,
+}
+
+impl LaunchBackend for Wine {
+    fn command(&self, executable: &str) -> Command {
+        let mut command = Command::new(&self.wine_path);
+        command.arg(executable);
+        if let Some(v) = &self.prefix {
+            command.env("WINEPREFIX", v);
+        }
+        command
+    }
+
+    fn game_path(&self, path: &Path) -> String {
+        wine_path(path)
+    }
+}
+
+/// Translates a local path to the Z: drive, which Wine and Proton map to the root folder.
+/// e.g. /home/user/a3mo/tmp -> Z:\home\user\a3mo\tmp
+fn wine_path(path: &Path) -> String {
+    let absolute = if path.is_absolute() {
+        path.to_path_buf()
+    } else {
+        std::env::current_dir()
+            .map(|v| v.join(path))
+            .unwrap_or_else(|_| path.to_path_buf())
+    };
+
+    let mut translated = String::from("Z:");
+    for component in absolute.components() {
+        let name: &OsStr = match component {
+            Component::Normal(v) => v,
+            Component::ParentDir => OsStr::new(".."),
+            _ => continue,
+        };
+        translated.push('\\');
+        translated.push_str(&name.to_string_lossy());
+    }
+    if translated.len() == 2 {
+        translated.push('\\');
+    }
+
+    translated
+}
+
+//Wine and Proton only run on unix, where paths start with '/'
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn absolute() {
+        assert_eq!(
+            wine_path(Path::new("/home/user/a3mo/tmp")),
+            "Z:\\home\\user\\a3mo\\tmp"
+        );
+    }
+
+    #[test]
+    fn parent_dir() {
+        assert_eq!(
+            wine_path(Path::new("/home/user/../a3mo/@ace")),
+            "Z:\\home\\user\\..\\a3mo\\@ace"
+        );
+    }
+
+    #[test]
+    fn relative() {
+        let absolute = std::env::current_dir().unwrap().join("tmp");
+        assert_eq!(wine_path(Path::new("tmp")), wine_path(&absolute));
+    }
+
+    #[test]
+    fn root() {
+        assert_eq!(wine_path(Path::new("/")), "Z:\\");
+    }
+}
@@ -0,0 +1,434 @@
+extern crate custom_error;
+use crate::repository::build::FileSystemEntity;
+use crate::repository::history;
+use crate::repository::signing;
+use crate::repository::signing::SigningError;
+use custom_error::custom_error;
+use flate2::read::GzDecoder;
+use indextree::Arena;
+use log::{debug, info};
+use reqwest;
+use serde::{Deserialize, Serialize};
+use serde_json;
+use std::collections::BTreeMap;
+use std::io::{BufRead, BufReader, Read};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+custom_error! {pub ManifestError
+    UnsupportedVersion{major: u32, minor: u32} = "Unsupported sync.json format version {major}.{minor}",
+    SerdeError{source: serde_json::Error} = "Serde Error",
+    SystemTimeErr{source: std::time::SystemTimeError} = "System Time Error",
+    IOError{source: std::io::Error} = "IO Error",
+    RequestError{source: reqwest::Error} = "Request Error",
+    Utf8Error{source: std::string::FromUtf8Error} = "UTF-8 Error",
+    SigningError{source: SigningError} = "Signing Error",
+    InvalidPath{path: String} = "Invalid path in manifest: {path}"
+}
+
+/// Major version of the sync.json format, manifests with another major version are rejected
+pub const FORMAT_MAJOR: u32 = 2;
+/// Minor version of the sync.json format, only adds fields older clients can ignore
+pub const FORMAT_MINOR: u32 = 0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct FormatVersion {
+    pub major: u32,
+    pub minor: u32,
+}
+
+/// File or folder of a manifest
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ManifestEntry {
+    pub is_folder: bool,
+    /// xxHash64, 0 for folders
+    #[serde(default)]
+    pub hash: u64,
+    /// Hex encoded BLAKE2b digest, empty for folders
+    #[serde(default)]
+    pub blake2b: String,
+    /// File size in byte, 0 for folders
+    #[serde(default)]
+    pub size: u64,
+    /// Last modification as unix timestamp (nanoseconds), 0 for folders.
+    /// Manifests of older versions store seconds, their files are hashed again on the next build.
+    #[serde(default)]
+    pub mtime: u64,
+}
+
+impl ManifestEntry {
+    pub fn of(fse: &FileSystemEntity) -> ManifestEntry {
+        ManifestEntry {
+            is_folder: fse.is_folder,
+            hash: fse.hash,
+            blake2b: String::from(&fse.blake2b),
+            size: fse.size,
+            mtime: fse.mtime,
+        }
+    }
+}
+
+/// Content of the sync.json
+///
+/// ```json
+/// {
+///   "format_version": { "major": 2, "minor": 0 },
+///   "generator": "a3mo_lib 0.3.1",
+///   "name": "repo",
+///   "revision": 3,
+///   "build_time": 1570000000,
+///   "public_key": "",
+///   "entries": {
+///     "@ace": { "is_folder": true },
+///     "@ace/mod.cpp": { "is_folder": false, "hash": 123, "blake2b": "8f1c...", "size": 42, "mtime": 1569999999123456789 }
+///   }
+/// }
+/// ```
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Manifest {
+    pub format_version: FormatVersion,
+    /// Library and version, which built the manifest
+    pub generator: String,
+    /// Repository name
+    pub name: String,
+    /// Increased by one on every build
+    pub revision: u64,
+    /// Unix timestamp (seconds) of the build
+    pub build_time: u64,
+    /// Hex encoded ed25519 key of the publisher, empty if the manifest is not signed
+    #[serde(default)]
+    pub public_key: String,
+    /// Files and folders keyed by their path relative to the repository folder, separated by '/'.
+    /// The repository folder itself is not listed, parents are always sorted before their children.
+    pub entries: BTreeMap<String, ManifestEntry>,
+}
+
+/// Only used to check the version before parsing the complete manifest
+#[derive(Deserialize)]
+struct VersionProbe {
+    format_version: Option<FormatVersion>,
+}
+
+/// sync.json format 1.x, which stored the serialized indextree arena
+#[derive(Deserialize)]
+struct ManifestV1 {
+    generator: String,
+    name: String,
+    revision: u64,
+    build_time: u64,
+    #[serde(default)]
+    public_key: String,
+    tree: Arena<FileSystemEntity>,
+}
+
+/// Normalizes a path relative to the repository folder to the manifest format
+pub fn normalize_path(name: &str) -> String {
+    name.replace('\\', "/").trim_matches('/').to_owned()
+}
+
+/// Path of the folder containing `path`, "" for the repository folder
+pub fn parent_path(path: &str) -> &str {
+    match path.rfind('/') {
+        Some(v) => &path[..v],
+        None => "",
+    }
+}
+
+/// Checks that `path` stays inside the repository folder on every platform.
+/// Rejects empty, "." and ".." components, drive prefixes and backslashes.
+pub(crate) fn check_path(path: &str) -> Result<(), ManifestError> {
+    let valid = path
+        .split('/')
+        .all(|c| !c.is_empty() && c != "." && c != ".." && !c.contains(&[':', '\\'][..]));
+    if valid {
+        Ok(())
+    } else {
+        Err(ManifestError::InvalidPath {
+            path: String::from(path),
+        })
+    }
+}
+
+/// Location of the manifest path `path` below the local folder `root`
+pub(crate) fn local_path(root: &Path, path: &str) -> PathBuf {
+    let mut local = root.to_path_buf();
+    local.extend(path.split('/').filter(|c| !c.is_empty()));
+    local
+}
+
+/// Converts the arena of format 0.x and 1.x into manifest entries
+fn entries_of(tree: &Arena<FileSystemEntity>) -> BTreeMap<String, ManifestEntry> {
+    tree.iter()
+        .map(|n| n.get())
+        .map(|fse| (normalize_path(&fse.name), ManifestEntry::of(fse)))
+        .filter(|(path, _)| !path.is_empty())
+        .collect()
+}
+
+impl Manifest {
+    /// New manifest of the current format, built now
+    pub fn new(
+        name: &str,
+        revision: u64,
+        entries: BTreeMap<String, ManifestEntry>,
+    ) -> Result<Manifest, ManifestError> {
+        Ok(Manifest {
+            format_version: FormatVersion {
+                major: FORMAT_MAJOR,
+                minor: FORMAT_MINOR,
+            },
+            generator: concat!("a3mo_lib ", env!("CARGO_PKG_VERSION")).to_owned(),
+            name: String::from(name),
+            revision,
+            build_time: SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs(),
+            public_key: String::new(),
+            entries,
+        })
+    }
+
+    /// Parses a sync.json.
+    /// Manifests without a version (bare arenas of a3mo_lib <= 0.3.0) are read as revision 0,
+    /// arenas of format 1.x are converted into entries.
+    /// Paths leaving the repository folder are rejected.
+    pub fn parse(json: &str) -> Result<Manifest, ManifestError> {
+        let probe: VersionProbe = serde_json::from_str(json)?;
+
+        let manifest = match probe.format_version {
+            Some(v) if v.major == FORMAT_MAJOR => serde_json::from_str(json)?,
+            Some(v) if v.major == 1 => {
+                let old: ManifestV1 = serde_json::from_str(json)?;
+                Manifest {
+                    format_version: v,
+                    generator: old.generator,
+                    name: old.name,
+                    revision: old.revision,
+                    build_time: old.build_time,
+                    public_key: old.public_key,
+                    entries: entries_of(&old.tree),
+                }
+            }
+            Some(v) => {
+                return Err(ManifestError::UnsupportedVersion {
+                    major: v.major,
+                    minor: v.minor,
+                })
+            }
+            None => {
+                let tree: Arena<FileSystemEntity> = serde_json::from_str(json)?;
+                Manifest {
+                    format_version: FormatVersion { major: 0, minor: 0 },
+                    generator: String::new(),
+                    name: String::new(),
+                    revision: 0,
+                    build_time: 0,
+                    public_key: String::new(),
+                    entries: entries_of(&tree),
+                }
+            }
+        };
+
+        for path in manifest.entries.keys() {
+            check_path(path)?;
+        }
+
+        Ok(manifest)
+    }
+
+    pub fn to_json(&self, fmt_json: bool) -> Result<String, ManifestError> {
+        if fmt_json {
+            Ok(serde_json::to_string_pretty(self)?)
+        } else {
+            Ok(serde_json::to_string(self)?)
+        }
+    }
+}
+
+/// Reads a local sync.json
+pub(crate) fn read(path: &Path) -> Result<Manifest, ManifestError> {
+    let json = std::fs::read_to_string(path)?;
+    Manifest::parse(&json)
+}
+
+/// Magic bytes at the start of every gzip file
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+/// Downloads the sync.json at `sync_url`, the compressed sync.json.gz is preferred.
+/// Any error while downloading or decompressing the sync.json.gz falls back to the plain sync.json
+fn download(sync_url: &str) -> Result<Vec<u8>, ManifestError> {
+    match download_compressed(&(sync_url.to_owned() + ".gz")) {
+        Ok(v) => return Ok(v),
+        Err(e) => debug!(
+            target: "a3mo::clone",
+            "No usable compressed manifest ({}), downloading {:?}",
+            e,
+            sync_url
+        ),
+    }
+
+    let mut data: Vec<u8> = Vec::new();
+    reqwest::get(sync_url)?
+        .error_for_status()?
+        .read_to_end(&mut data)?;
+
+    Ok(data)
+}
+
+/// Downloads and decompresses the sync.json.gz at `gz_url`
+fn download_compressed(gz_url: &str) -> Result<Vec<u8>, ManifestError> {
+    let mut data: Vec<u8> = Vec::new();
+
+    let gz_resp = reqwest::get(gz_url)?.error_for_status()?;
+    let mut reader = BufReader::new(gz_resp);
+    //Servers sending .gz files with Content-Encoding: gzip are already decompressed by reqwest
+    if reader.fill_buf()?.starts_with(&GZIP_MAGIC) {
+        GzDecoder::new(reader).read_to_end(&mut data)?;
+    } else {
+        reader.read_to_end(&mut data)?;
+    }
+
+    Ok(data)
+}
+
+/// Downloads and parses the sync.json of the a3mo folder at `url`.
+/// Signed manifests are verified against their sync.json.sig.
+/// * `pinned_key` : Publisher key of the repository, the manifest has to be signed with it if given
+pub(crate) fn fetch(url: &str, pinned_key: Option<&str>) -> Result<Manifest, ManifestError> {
+    fetch_from(&(url.to_owned() + "/sync.json"), pinned_key)
+}
+
+/// Downloads and parses the manifest of a published revision of the a3mo folder at `url`
+pub(crate) fn fetch_revision(
+    url: &str,
+    revision: u64,
+    pinned_key: Option<&str>,
+) -> Result<Manifest, ManifestError> {
+    fetch_from(
+        &(url.to_owned() + "/revisions/" + &history::revision_name(revision)),
+        pinned_key,
+    )
+}
+
+/// Time to wait before fetching a manifest again, whose signature did not match
+const SIGNATURE_RETRY_DELAY: Duration = Duration::from_secs(2);
+
+/// Downloads and verifies the manifest at `sync_url`.
+/// The sync.json and its signature are not replaced at the same time while publishing,
+/// a mismatching or missing signature is therefore retried once.
+fn fetch_from(sync_url: &str, pinned_key: Option<&str>) -> Result<Manifest, ManifestError> {
+    match fetch_verified(sync_url, pinned_key) {
+        Err(ManifestError::SigningError {
+            source: e @ SigningError::SignatureMismatch,
+        })
+        | Err(ManifestError::SigningError {
+            source: e @ SigningError::MissingSignature,
+        }) => {
+            debug!(
+                target: "a3mo::clone",
+                "{} for {:?}, the repository may be publishing. Retrying",
+                e,
+                sync_url
+            );
+            std::thread::sleep(SIGNATURE_RETRY_DELAY);
+            fetch_verified(sync_url, pinned_key)
+        }
+        v => v,
+    }
+}
+
+fn fetch_verified(sync_url: &str, pinned_key: Option<&str>) -> Result<Manifest, ManifestError> {
+    let jstring = String::from_utf8(download(sync_url)?)?;
+
+    let manifest = Manifest::parse(jstring.as_str())?;
+
+    let key = match pinned_key {
+        Some(v) if !v.is_empty() => {
+            if !manifest.public_key.is_empty() && manifest.public_key != v {
+                return Err(SigningError::KeyMismatch.into());
+            }
+            v
+        }
+        _ => manifest.public_key.as_str(),
+    };
+
+    if !key.is_empty() {
+        let mut sig_resp = reqwest::get(&(sync_url.to_owned() + ".sig"))?;
+        if !sig_resp.status().is_success() {
+            return Err(SigningError::MissingSignature.into());
+        }
+        let signature = sig_resp.text()?;
+        signing::verify(key, jstring.as_bytes(), &signature)?;
+        info!(target: "a3mo::clone", "Manifest signed by {:?}", key);
+    }
+
+    Ok(manifest)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Arena of a3mo_lib <= 0.3.0, built on Windows
+    const ARENA: &str = r#"{"nodes":[
+    {"parent":null,"previous_sibling":null,"next_sibling":null,"first_child":{"index1":2,"stamp":0},"last_child":{"index1":2,"stamp":0},"stamp":0,"data":{"Data":{"name":"","is_folder":true,"hash":0}}},
+    {"parent":{"index1":1,"stamp":0},"previous_sibling":null,"next_sibling":null,"first_child":{"index1":3,"stamp":0},"last_child":{"index1":5,"stamp":0},"stamp":0,"data":{"Data":{"name":"@ace","is_folder":true,"hash":0}}},
+    {"parent":{"index1":2,"stamp":0},"previous_sibling":null,"next_sibling":{"index1":5,"stamp":0},"first_child":{"index1":4,"stamp":0},"last_child":{"index1":4,"stamp":0},"stamp":0,"data":{"Data":{"name":"@ace\\addons","is_folder":true,"hash":0}}},
+    {"parent":{"index1":3,"stamp":0},"previous_sibling":null,"next_sibling":null,"first_child":null,"last_child":null,"stamp":0,"data":{"Data":{"name":"@ace\\addons\\ace_common.pbo","is_folder":false,"hash":123}}},
+    {"parent":{"index1":2,"stamp":0},"previous_sibling":{"index1":3,"stamp":0},"next_sibling":null,"first_child":null,"last_child":null,"stamp":0,"data":{"Data":{"name":"@ace\\mod.cpp","is_folder":false,"hash":456}}}
+],"first_free_slot":null,"last_free_slot":null}"#;
+
+    fn assert_entries(manifest: &Manifest) {
+        let paths: Vec<&str> = manifest.entries.keys().map(|k| k.as_str()).collect();
+        assert_eq!(
+            paths,
+            vec![
+                "@ace",
+                "@ace/addons",
+                "@ace/addons/ace_common.pbo",
+                "@ace/mod.cpp"
+            ]
+        );
+        assert!(manifest.entries["@ace/addons"].is_folder);
+        let file = &manifest.entries["@ace/addons/ace_common.pbo"];
+        assert!(!file.is_folder);
+        assert_eq!(file.hash, 123);
+        assert!(file.blake2b.is_empty());
+    }
+
+    #[test]
+    fn parse_arena() {
+        let manifest = Manifest::parse(ARENA).unwrap();
+        assert_eq!(
+            manifest.format_version,
+            FormatVersion { major: 0, minor: 0 }
+        );
+        assert_eq!(manifest.revision, 0);
+        assert_entries(&manifest);
+    }
+
+    #[test]
+    fn parse_v1() {
+        let json = format!(
+            r#"{{"format_version":{{"major":1,"minor":0}},"generator":"a3mo_lib 0.3.1","name":"repo","revision":3,"build_time":1570000000,"tree":{}}}"#,
+            ARENA
+        );
+        let manifest = Manifest::parse(&json).unwrap();
+        assert_eq!(
+            manifest.format_version,
+            FormatVersion { major: 1, minor: 0 }
+        );
+        assert_eq!(manifest.name, "repo");
+        assert_eq!(manifest.revision, 3);
+        assert_entries(&manifest);
+    }
+
+    #[test]
+    fn unsupported_version() {
+        let json = r#"{"format_version":{"major":3,"minor":1},"entries":{}}"#;
+        match Manifest::parse(json) {
+            Err(ManifestError::UnsupportedVersion { major, minor }) => {
+                assert_eq!((major, minor), (3, 1))
+            }
+            v => panic!("Expected UnsupportedVersion, got {:?}", v),
+        }
+    }
+}
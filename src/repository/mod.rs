@@ -1,4 +1,20 @@
 pub mod build;
+pub mod cancel;
+pub mod checkout;
 pub mod clone;
+pub mod collision;
+mod delta;
+pub mod diff;
+pub mod gc;
+mod hash;
+pub mod history;
+pub mod launch;
+pub mod manifest;
 pub mod new;
+pub mod progress;
 pub mod run;
+pub mod signing;
+pub mod staging;
+mod store;
+pub mod update;
+pub mod verify;
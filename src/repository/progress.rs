@@ -0,0 +1,92 @@
+use std::sync::mpsc::Sender;
+use std::sync::Mutex;
+
+/// Phase of a long running operation
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Phase {
+    /// Searching the repository folder for files (build)
+    Scanning,
+    /// Hashing files and generating signatures (build)
+    Hashing,
+    /// Generating patches from the previous build (build)
+    Patching,
+    /// Writing the sync.json (build)
+    Writing,
+    /// Downloading the sync.json (clone, update)
+    FetchingManifest,
+    /// Writing the repository into the database (clone, update)
+    UpdatingDatabase,
+    /// Downloading missing files (clone, update)
+    Downloading,
+    /// Linking the repository into the tmp folder (run)
+    Staging,
+    /// Starting ArmA3 (run)
+    Launching,
+    Finished,
+}
+
+/// Structured progress information emitted by build, clone, update and run
+#[derive(Debug, Clone)]
+pub enum ProgressEvent {
+    Phase(Phase),
+    /// Amount of files found inside the repository folder
+    FilesDiscovered(usize),
+    /// Amount of files and bytes the current phase will process, bytes are unknown for downloads
+    Totals {
+        files: usize,
+        bytes: Option<u64>,
+    },
+    HashStarted {
+        name: String,
+    },
+    HashFinished {
+        name: String,
+        hash: u64,
+    },
+    /// Bytes of `name` downloaded so far, `total` is the size announced by the server
+    Downloading {
+        name: String,
+        downloaded: u64,
+        total: Option<u64>,
+    },
+    /// `name` was stored, `bytes` were transferred to do so
+    DownloadFinished {
+        name: String,
+        bytes: u64,
+    },
+}
+
+/// Receiver of progress events.
+/// Events are emitted from multiple threads, if rayon is used.
+pub trait Progress: Sync {
+    fn event(&self, event: ProgressEvent);
+}
+
+/// Ignores all events
+pub struct NoProgress;
+
+impl Progress for NoProgress {
+    fn event(&self, _event: ProgressEvent) {}
+}
+
+/// Forwards all events into a channel
+pub struct ChannelProgress {
+    sender: Mutex<Sender<ProgressEvent>>,
+}
+
+impl ChannelProgress {
+    pub fn new(sender: Sender<ProgressEvent>) -> ChannelProgress {
+        ChannelProgress {
+            sender: Mutex::new(sender),
+        }
+    }
+}
+
+impl Progress for ChannelProgress {
+    fn event(&self, event: ProgressEvent) {
+        if let Ok(sender) = self.sender.lock() {
+            //The receiver might already be gone, progress is optional
+            let _ = sender.send(event);
+        }
+    }
+}
@@ -1,13 +1,21 @@
 extern crate custom_error;
+use crate::repository::launch::LaunchBackend;
+use crate::repository::manifest;
+use crate::repository::progress::{Phase, Progress, ProgressEvent};
+use crate::repository::staging;
+use crate::repository::staging::StagingStrategy;
+use crate::repository::store;
 use crate::sql::sqlite;
 use custom_error::custom_error;
-use std::process::Command;
+use log::{debug, info};
+use std::path::Path;
 use std::time::SystemTime;
 
 extern crate rusqlite;
 
 custom_error! {pub RunError
     FileNotFound = "File not found error",
+    StagingError{source: std::io::Error, path: String} = "Could not stage {path}: {source}",
     SQLError{source: rusqlite::Error} = "SQL Error",
     SystemTimeErr{source: std::time::SystemTimeError} = "System Time Error",
     IOError{source: std::io::Error} = "IO Error"
@@ -18,41 +26,63 @@ custom_error! {pub RunError
 /// * `arma_path` : Path to Arma3 executable
 /// * `tmp_folder` : Path to tmp folder
 /// * `opt_args` : Optional arguments
+/// * `backend` : Starts the executable, e.g. launch::Native on Windows or launch::Proton on Linux
+/// * `staging` : Ways of placing the files into the tmp folder, tried in order (e.g. StagingStrategy::DEFAULT_ORDER)
+/// * `progress` : Receives progress events
 pub fn run(
     name: &str,
     arma_path: &str,
     tmp_folder: &str,
     opt_args: Option<Vec<String>>,
+    backend: &dyn LaunchBackend,
+    staging: &[StagingStrategy],
+    progress: &dyn Progress,
 ) -> Result<(), RunError> {
     let start = SystemTime::now();
 
     let mut conn = sqlite::get_conn()?;
     let repository = sqlite::get_repository(&name, &mut conn)?;
 
+    //Repositories cloned by older versions store names with backslashes
+    sqlite::normalize_names(repository.id, &conn)?;
+
     let repo_folders = sqlite::get_repo_folders(repository.id, &mut conn)?;
     let repo_files = sqlite::get_repo_files(repository.id, &mut conn)?;
 
-    if tmp_folder.chars().nth(0) != repository.path.chars().nth(0) {
-        println!("Both folders have to be on the same drive");
-    }
+    progress.event(ProgressEvent::Phase(Phase::Staging));
+    progress.event(ProgressEvent::Totals {
+        files: repo_files.len(),
+        bytes: None,
+    });
 
     //Cleanup old folder
     std::fs::remove_dir_all(tmp_folder).unwrap_or_default();
 
     for repo_folder in &repo_folders {
-        let xfolder = tmp_folder.to_owned() + "\\" + &repo_folder.name;
-        println!("{:?}", xfolder);
+        let xfolder = manifest::local_path(Path::new(tmp_folder), &repo_folder.name);
+        debug!(target: "a3mo::run", "{:?}", xfolder);
         std::fs::create_dir_all(xfolder)?;
     }
 
-    for repo_file in &repo_files {
-        let dfile = tmp_folder.to_owned() + "\\" + &repo_file.name;
-        let sfile = repository.path.to_owned() + "\\" + &repo_file.xx_hash64;
-
-        println!("{:?} -> {:?}", dfile, sfile);
+    let mut strategies =
+        staging::usable(staging, Path::new(&repository.path), Path::new(tmp_folder));
 
-        //Same drive only !!!!
-        std::fs::hard_link(sfile, dfile)?;
+    for repo_file in &repo_files {
+        let dfile = manifest::local_path(Path::new(tmp_folder), &repo_file.name);
+        let sfile = store::blob_path(
+            &repository.path,
+            &store::blob_name(&repo_file.xx_hash64, &repo_file.blake2b),
+        );
+
+        debug!(target: "a3mo::run", "{:?} -> {:?}", dfile, sfile);
+
+        let strategy = staging::stage(&mut strategies, &sfile, &dfile).map_err(|e| {
+            RunError::StagingError {
+                source: e,
+                path: String::from(&repo_file.name),
+            }
+        })?;
+        debug!(target: "a3mo::run", "{:?} {:?}", strategy, &repo_file.name);
     }
 
     let mut args: Vec<String> = Vec::new();
@@ -66,24 +96,25 @@ pub fn run(
             continue;
         }
         let fchar = repo_folder.name.chars().nth(0);
-        if fchar.unwrap() == '@' && !repo_folder.name.contains('\\') {
-            println!("{:?}", repo_folder.name);
+        if fchar.unwrap() == '@' && !repo_folder.name.contains('/') {
+            debug!(target: "a3mo::run", "{:?}", repo_folder.name);
             let f = "-mod=".to_owned()
-                + &tmp_folder.to_owned()
-                + "\\"
-                + String::from(&repo_folder.name).as_str()
+                + &backend.game_path(&Path::new(tmp_folder).join(&repo_folder.name))
                 + ";";
             args.push(f);
         }
     }
 
-    println!("{:?}", arma_path);
-    println!("{:?}", args);
+    progress.event(ProgressEvent::Phase(Phase::Launching));
+
+    info!(target: "a3mo::run", "{:?}", arma_path);
+    info!(target: "a3mo::run", "{:?}", args);
 
-    let _fx = Command::new(arma_path).args(args).spawn();
+    let _fx = backend.command(arma_path).args(args).spawn()?;
 
     let elapsed = start.elapsed()?;
 
-    println!("Elapsed: {:?}", elapsed);
+    progress.event(ProgressEvent::Phase(Phase::Finished));
+    info!(target: "a3mo::run", "Elapsed: {:?}", elapsed);
     Ok(())
 }
@@ -0,0 +1,86 @@
+extern crate custom_error;
+use custom_error::custom_error;
+use ed25519_dalek::{Keypair, PublicKey, Signature, Signer, Verifier};
+use std::convert::TryFrom;
+use std::fs::OpenOptions;
+use std::io::Write;
+
+custom_error! {pub SigningError
+    InvalidKey = "Invalid key",
+    InvalidSignature = "Invalid signature",
+    SignatureMismatch = "Signature does not match the publisher key",
+    KeyMismatch = "Manifest is signed by another key than the pinned one",
+    MissingSignature = "Manifest of a signed repository is not signed",
+    IOError{source: std::io::Error} = "IO Error"
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    let mut hex = vec![0u8; bytes.len() * 2];
+    faster_hex::hex_encode_fallback(bytes, &mut hex);
+    String::from_utf8(hex).unwrap_or_default()
+}
+
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    let hex = hex.trim();
+    if hex.len() % 2 != 0 || !faster_hex::hex_check_fallback(hex.as_bytes()) {
+        return None;
+    }
+    let mut bytes = vec![0u8; hex.len() / 2];
+    faster_hex::hex_decode(hex.as_bytes(), &mut bytes).ok()?;
+    Some(bytes)
+}
+
+/// Generates a new publisher keypair and stores it hex encoded at `path`.
+/// The keypair file must never be published, an existing file is not overwritten.
+/// On unix, the file is only readable and writable by its owner.
+/// * `path` : Path of the keypair file
+///
+/// Returns the hex encoded public key
+pub fn generate_keypair(path: &str) -> Result<String, SigningError> {
+    let keypair = Keypair::generate(&mut rand::rngs::OsRng);
+
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    //Only readable by the owner, the default umask usually makes it world-readable
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+
+    let mut file = options.open(path)?;
+    file.write_all(to_hex(&keypair.to_bytes()).as_bytes())?;
+
+    Ok(public_key(&keypair))
+}
+
+/// Reads a keypair written by generate_keypair
+pub(crate) fn read_keypair(path: &str) -> Result<Keypair, SigningError> {
+    let hex = std::fs::read_to_string(path)?;
+    let bytes = from_hex(&hex).ok_or(SigningError::InvalidKey)?;
+
+    Keypair::from_bytes(&bytes).map_err(|_| SigningError::InvalidKey)
+}
+
+/// Hex encoded public key of `keypair`
+pub(crate) fn public_key(keypair: &Keypair) -> String {
+    to_hex(keypair.public.as_bytes())
+}
+
+/// Signs `data`, returns the hex encoded signature
+pub(crate) fn sign(keypair: &Keypair, data: &[u8]) -> String {
+    to_hex(&keypair.sign(data).to_bytes())
+}
+
+/// Checks the hex encoded `signature` of `data` against the hex encoded `public_key`
+pub(crate) fn verify(public_key: &str, data: &[u8], signature: &str) -> Result<(), SigningError> {
+    let key_bytes = from_hex(public_key).ok_or(SigningError::InvalidKey)?;
+    let key = PublicKey::from_bytes(&key_bytes).map_err(|_| SigningError::InvalidKey)?;
+
+    let sig_bytes = from_hex(signature).ok_or(SigningError::InvalidSignature)?;
+    let sig =
+        Signature::try_from(sig_bytes.as_slice()).map_err(|_| SigningError::InvalidSignature)?;
+
+    key.verify(data, &sig)
+        .map_err(|_| SigningError::SignatureMismatch)
+}
@@ -0,0 +1,175 @@
+use log::{debug, info};
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// Way of placing a blob of the content store into the tmp folder
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StagingStrategy {
+    /// Copy-on-write clone (Btrfs, XFS, APFS, ReFS), same drive only
+    Reflink,
+    /// Symbolic link, requires the developer mode or administrator rights on Windows
+    Symlink,
+    /// Hard link, same drive only
+    HardLink,
+    /// Full copy, always possible but needs the space of the whole repository
+    Copy,
+}
+
+impl StagingStrategy {
+    /// Order used by earlier versions, with fallbacks for other drives
+    pub const DEFAULT_ORDER: [StagingStrategy; 4] = [
+        StagingStrategy::HardLink,
+        StagingStrategy::Reflink,
+        StagingStrategy::Symlink,
+        StagingStrategy::Copy,
+    ];
+
+    fn same_device_only(self) -> bool {
+        match self {
+            StagingStrategy::Reflink | StagingStrategy::HardLink => true,
+            StagingStrategy::Symlink | StagingStrategy::Copy => false,
+        }
+    }
+
+    /// Checks if `e` shows, that the strategy does not work between the two folders at all.
+    /// Other errors concern a single file, e.g. a missing or locked blob
+    fn is_unsupported(self, e: &io::Error) -> bool {
+        if e.raw_os_error() == Some(CROSS_DEVICE) || e.kind() == io::ErrorKind::Unsupported {
+            return true;
+        }
+
+        match self {
+            //File systems without copy-on-write reject the clone in different ways
+            StagingStrategy::Reflink => {
+                e.kind() == io::ErrorKind::InvalidInput
+                    || e.raw_os_error() == Some(NO_CLONE_SUPPORT)
+                    || (e.kind() == io::ErrorKind::Other && e.raw_os_error().is_none())
+            }
+            StagingStrategy::Symlink => {
+                e.kind() == io::ErrorKind::PermissionDenied
+                    || (e.raw_os_error().is_some() && e.raw_os_error() == NO_SYMLINK_PRIVILEGE)
+            }
+            StagingStrategy::HardLink | StagingStrategy::Copy => false,
+        }
+    }
+
+    fn stage(self, source: &Path, target: &Path) -> io::Result<()> {
+        match self {
+            StagingStrategy::Reflink => reflink::reflink(source, target),
+            StagingStrategy::Symlink => symlink(&fs::canonicalize(source)?, target),
+            StagingStrategy::HardLink => fs::hard_link(source, target),
+            StagingStrategy::Copy => fs::copy(source, target).map(|_| ()),
+        }
+    }
+}
+
+/// EXDEV
+#[cfg(unix)]
+const CROSS_DEVICE: i32 = 18;
+/// ENOTTY, the clone ioctl is unknown to the file system
+#[cfg(unix)]
+const NO_CLONE_SUPPORT: i32 = 25;
+#[cfg(unix)]
+const NO_SYMLINK_PRIVILEGE: Option<i32> = None;
+
+/// ERROR_NOT_SAME_DEVICE
+#[cfg(windows)]
+const CROSS_DEVICE: i32 = 17;
+/// ERROR_INVALID_FUNCTION, the file system is not ReFS
+#[cfg(windows)]
+const NO_CLONE_SUPPORT: i32 = 1;
+/// ERROR_PRIVILEGE_NOT_HELD, symbolic links require the developer mode or administrator rights
+#[cfg(windows)]
+const NO_SYMLINK_PRIVILEGE: Option<i32> = Some(1314);
+
+#[cfg(unix)]
+fn symlink(source: &Path, target: &Path) -> io::Result<()> {
+    std::os::unix::fs::symlink(source, target)
+}
+
+#[cfg(windows)]
+fn symlink(source: &Path, target: &Path) -> io::Result<()> {
+    std::os::windows::fs::symlink_file(source, target)
+}
+
+/// Checks if both existing folders are on the same device, None if unknown
+#[cfg(unix)]
+fn same_device(a: &Path, b: &Path) -> Option<bool> {
+    use std::os::unix::fs::MetadataExt;
+
+    Some(fs::metadata(a).ok()?.dev() == fs::metadata(b).ok()?.dev())
+}
+
+/// Checks if both existing folders are on the same drive, None if unknown.
+/// Folders mounted into another drive are not detected.
+#[cfg(windows)]
+fn same_device(a: &Path, b: &Path) -> Option<bool> {
+    let prefix = |p: &Path| -> Option<std::path::PathBuf> {
+        Some(
+            fs::canonicalize(p)
+                .ok()?
+                .components()
+                .next()?
+                .as_os_str()
+                .into(),
+        )
+    };
+
+    Some(prefix(a)? == prefix(b)?)
+}
+
+/// Strategies of `order`, which can work between the content store at `store_path` and `tmp_folder`
+pub(crate) fn usable(
+    order: &[StagingStrategy],
+    store_path: &Path,
+    tmp_folder: &Path,
+) -> Vec<StagingStrategy> {
+    let same_device = same_device(store_path, tmp_folder);
+    if same_device == Some(false) {
+        info!(
+            target: "a3mo::run",
+            "{:?} and {:?} are on different drives, no links possible",
+            store_path, tmp_folder
+        );
+    }
+
+    order
+        .iter()
+        .cloned()
+        .filter(|s| !s.same_device_only() || same_device != Some(false))
+        .collect()
+}
+
+/// Places `source` at `target` using the first working strategy.
+/// A strategy, which turned out to be unsupported, is removed from `strategies` and not tried for further files.
+/// Errors concerning only this file are returned without trying further strategies.
+/// Returns the strategy used
+pub(crate) fn stage(
+    strategies: &mut Vec<StagingStrategy>,
+    source: &Path,
+    target: &Path,
+) -> io::Result<StagingStrategy> {
+    //No strategy left, all of them are unsupported
+    let mut last_error = io::Error::from(io::ErrorKind::Unsupported);
+
+    while let Some(strategy) = strategies.first().cloned() {
+        match strategy.stage(source, target) {
+            Ok(()) => return Ok(strategy),
+            Err(e) if strategy.is_unsupported(&e) => {
+                debug!(
+                    target: "a3mo::run",
+                    "{:?} is not supported, trying next strategy. Err: {:?}",
+                    strategy, e
+                );
+                //Remains of the failed attempt
+                fs::remove_file(target).unwrap_or_default();
+                strategies.remove(0);
+                last_error = e;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+
+    Err(last_error)
+}
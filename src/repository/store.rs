@@ -0,0 +1,174 @@
+use crate::repository::hash::ContentHash;
+use crate::sql::sqlite;
+use log::debug;
+use rusqlite::{Connection, Result};
+use std::collections::HashSet;
+use std::ffi::OsString;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Path of the blob with the given hash inside the content store at `path`
+pub(crate) fn blob_path(path: &str, hash: &str) -> PathBuf {
+    Path::new(path).join(hash)
+}
+
+/// `path` with `suffix` appended to the file name, e.g. "file" -> "file.part"
+pub(crate) fn append_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name: OsString = path.as_os_str().to_owned();
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+/// Name of the blob of a file, the BLAKE2b digest if known, otherwise the xxHash64
+pub(crate) fn blob_name(xx_hash: &str, blake2b: &str) -> String {
+    if blake2b.is_empty() {
+        String::from(xx_hash)
+    } else {
+        String::from(blake2b)
+    }
+}
+
+/// Blobs are named by their hex encoded BLAKE2b digest, or by their xxHash64 if stored by an older version
+fn is_blob_name(name: &str) -> bool {
+    name.parse::<u64>().is_ok() || (name.len() == 64 && name.chars().all(|c| c.is_ascii_hexdigit()))
+}
+
+/// Suffixes of unfinished downloads (.part) and rebuilds from a previous version (.delta)
+const LEFTOVER_SUFFIXES: [&str; 2] = [".part", ".delta"];
+
+/// Lists all files inside the content store at `path`, whose name is valid UTF-8, as (name, size)
+fn list_files(path: &str) -> io::Result<Vec<(String, u64)>> {
+    let mut files: Vec<(String, u64)> = Vec::new();
+
+    for entry in fs::read_dir(path)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        if !metadata.is_file() {
+            continue;
+        }
+
+        if let Some(name) = entry.file_name().to_str() {
+            files.push((String::from(name), metadata.len()));
+        }
+    }
+
+    Ok(files)
+}
+
+/// Lists all blobs inside the content store at `path` as (hash, size)
+pub(crate) fn list_blobs(path: &str) -> io::Result<Vec<(String, u64)>> {
+    Ok(list_files(path)?
+        .into_iter()
+        .filter(|(name, _)| is_blob_name(name))
+        .collect())
+}
+
+/// Lists unfinished downloads and rebuilds inside the content store at `path` as (name, hash of their blob, size)
+pub(crate) fn list_leftovers(path: &str) -> io::Result<Vec<(String, String, u64)>> {
+    let mut leftovers: Vec<(String, String, u64)> = Vec::new();
+
+    for (name, size) in list_files(path)? {
+        for suffix in &LEFTOVER_SUFFIXES {
+            if name.ends_with(suffix) {
+                let blob = &name[..name.len() - suffix.len()];
+                if is_blob_name(blob) {
+                    leftovers.push((name.clone(), String::from(blob), size));
+                }
+            }
+        }
+    }
+
+    Ok(leftovers)
+}
+
+/// Identity of the content store at `path`, the same folder written differently has the same identity.
+/// Falls back to the path itself, if it can not be resolved (e.g. not created yet)
+fn store_id(path: &str) -> PathBuf {
+    fs::canonicalize(path).unwrap_or_else(|_| PathBuf::from(path))
+}
+
+/// Hashes referenced by any repository, which stores its files inside `path`
+pub(crate) fn referenced_hashes(path: &str, conn: &mut Connection) -> Result<HashSet<String>> {
+    let mut hashes: HashSet<String> = HashSet::new();
+    let id = store_id(path);
+
+    for repository in sqlite::get_repositories(conn)? {
+        if store_id(&repository.path) != id {
+            continue;
+        }
+        for file in sqlite::get_repo_files(repository.id, conn)? {
+            hashes.insert(blob_name(&file.xx_hash64, &file.blake2b));
+        }
+    }
+
+    Ok(hashes)
+}
+
+/// Content stores of all other repositories, the store at `path` is not part of them
+pub(crate) fn other_stores(path: &str, conn: &mut Connection) -> Result<Vec<String>> {
+    let mut stores: Vec<String> = Vec::new();
+    let mut ids: HashSet<PathBuf> = HashSet::new();
+    ids.insert(store_id(path));
+
+    for repository in sqlite::get_repositories(conn)? {
+        if ids.insert(store_id(&repository.path)) {
+            stores.push(repository.path);
+        }
+    }
+
+    Ok(stores)
+}
+
+/// Path of the unfinished download of `blob`
+pub(crate) fn partial_path(blob: &Path) -> PathBuf {
+    append_suffix(blob, ".part")
+}
+
+/// Makes a blob stored under its xxHash64 by an older version available under its BLAKE2b digest.
+/// The old blob is kept for repositories, which were not updated yet, until it is garbage collected.
+/// Returns false, if there is no matching old blob
+pub(crate) fn migrate_blob(path: &str, hash: &ContentHash) -> io::Result<bool> {
+    if hash.blake2b.is_empty() {
+        return Ok(false);
+    }
+
+    let old = blob_path(path, &hash.xx_hash.to_string());
+    if !old.exists() || !hash.matches(&old)? {
+        return Ok(false);
+    }
+
+    let new = blob_path(path, &hash.blake2b);
+    if fs::hard_link(&old, &new).is_err() {
+        fs::copy(&old, &new)?;
+    }
+    debug!(target: "a3mo::clone", "Migrated blob {:?} -> {:?}", &old, &new);
+
+    Ok(true)
+}
+
+/// Links or copies a blob from another content store into the one at `path`, instead of downloading it.
+/// Returns false, if no other store has a matching blob
+pub(crate) fn import_blob(stores: &[String], path: &str, hash: &ContentHash) -> io::Result<bool> {
+    let blob = hash.blob_name();
+    let new = blob_path(path, &blob);
+
+    for store in stores {
+        let old = blob_path(store, &blob);
+        if !old.exists() || !hash.matches(&old)? {
+            continue;
+        }
+
+        //Hard links only work on the same drive
+        if fs::hard_link(&old, &new).is_err() {
+            let partpath = partial_path(&new);
+            fs::copy(&old, &partpath)?;
+            fs::rename(&partpath, &new)?;
+        }
+        debug!(target: "a3mo::clone", "Reused blob {:?} -> {:?}", &old, &new);
+
+        return Ok(true);
+    }
+
+    Ok(false)
+}
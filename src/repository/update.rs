@@ -0,0 +1,255 @@
+extern crate custom_error;
+use crate::repository::cancel::CancellationToken;
+use crate::repository::clone;
+use crate::repository::clone::{CloneError, DownloadFailure};
+use crate::repository::diff;
+use crate::repository::diff::Change;
+use crate::repository::history::HistoryError;
+use crate::repository::manifest;
+use crate::repository::manifest::{Manifest, ManifestEntry, ManifestError};
+use crate::repository::progress::{Phase, Progress, ProgressEvent};
+use crate::repository::store;
+use crate::sql::sqlite;
+use crate::sql::sqlite::{RFile, RFolder, Repository};
+use custom_error::custom_error;
+use log::info;
+use rusqlite::Connection;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::time::SystemTime;
+
+extern crate rusqlite;
+
+custom_error! {pub UpdateError
+    SQLError{source: rusqlite::Error} = "SQL Error",
+    SystemTimeErr{source: std::time::SystemTimeError} = "System Time Error",
+    IOError{source: std::io::Error} = "IO Error",
+    CloneError{source: CloneError} = "Clone Error",
+    ManifestError{source: ManifestError} = "Manifest Error",
+    HistoryError{source: HistoryError} = "History Error",
+    RevisionUnavailable{revision: u64, count: usize} = "{count} files of revision {revision} are neither stored locally nor published anymore",
+    Cancelled = "Update cancelled"
+}
+
+/// Changes applied by an update
+#[derive(Debug, Default)]
+pub struct UpdateReport {
+    /// Remote revision the repository was updated to
+    pub revision: u64,
+    pub added: Vec<String>,
+    pub modified: Vec<String>,
+    pub removed: Vec<String>,
+    /// (old name, new name) of files and folders, which only changed their path and are not downloaded again
+    pub moved: Vec<(String, String)>,
+    pub downloaded_bytes: u64,
+    pub failures: Vec<DownloadFailure>,
+    /// The repository is pinned to `revision`: update skipped it, or checkout pinned it
+    pub pinned: bool,
+}
+
+/// Updates an already cloned repository to the current remote state.
+/// * `name` : Repository name (as given to the clone command)
+/// * `progress` : Receives progress events
+/// * `cancel` : Stops the update, the database keeps its previous state if cancelled before downloading
+pub fn update(
+    name: &str,
+    progress: &dyn Progress,
+    cancel: &CancellationToken,
+) -> Result<UpdateReport, UpdateError> {
+    info!(target: "a3mo::update", "Updating repository {:?}", &name);
+    let start = SystemTime::now();
+
+    let mut conn = sqlite::get_conn()?;
+    let repository = sqlite::get_repository(name, &mut conn)?;
+
+    if let Some(v) = repository.pinned_revision {
+        info!(target: "a3mo::update", "Repository is pinned to revision {:?}, skipping", v);
+        return Ok(UpdateReport {
+            revision: v,
+            pinned: true,
+            ..UpdateReport::default()
+        });
+    }
+
+    let report = update_repository(&repository, &mut conn, progress, cancel)?;
+
+    info!(
+        target: "a3mo::update",
+        "Finished updating. {:?} added, {:?} modified, {:?} removed, {:?} moved, {:?} byte in {:?} sec",
+        report.added.len(),
+        report.modified.len(),
+        report.removed.len(),
+        report.moved.len(),
+        report.downloaded_bytes,
+        start.elapsed()?
+    );
+
+    Ok(report)
+}
+
+/// Updates `repository` to the current remote state, even if it is pinned
+pub(crate) fn update_repository(
+    repository: &Repository,
+    conn: &mut Connection,
+    progress: &dyn Progress,
+    cancel: &CancellationToken,
+) -> Result<UpdateReport, UpdateError> {
+    progress.event(ProgressEvent::Phase(Phase::FetchingManifest));
+    let manifest = manifest::fetch(&repository.url, Some(&repository.public_key))?;
+
+    sync_to(repository, &manifest, conn, progress, cancel)
+}
+
+/// Manifest of the local state of `repository`, sizes are not stored locally
+fn local_manifest(
+    repository: &Repository,
+    folders: &HashMap<String, RFolder>,
+    files: &HashMap<String, RFile>,
+) -> Result<Manifest, ManifestError> {
+    let mut entries: BTreeMap<String, ManifestEntry> = BTreeMap::new();
+    for (fname, folder) in folders {
+        if !folder.is_root {
+            entries.insert(
+                String::from(fname),
+                ManifestEntry {
+                    is_folder: true,
+                    hash: 0,
+                    blake2b: String::new(),
+                    size: 0,
+                    mtime: 0,
+                },
+            );
+        }
+    }
+    for (fname, file) in files {
+        entries.insert(
+            String::from(fname),
+            ManifestEntry {
+                is_folder: false,
+                hash: file.xx_hash64.parse().unwrap_or(0),
+                blake2b: String::from(&file.blake2b),
+                size: 0,
+                mtime: 0,
+            },
+        );
+    }
+
+    Manifest::new(&repository.name, 0, entries)
+}
+
+/// Brings the database rows and the content store of `repository` to the state of `manifest`
+pub(crate) fn sync_to(
+    repository: &Repository,
+    manifest: &Manifest,
+    conn: &mut Connection,
+    progress: &dyn Progress,
+    cancel: &CancellationToken,
+) -> Result<UpdateReport, UpdateError> {
+    progress.event(ProgressEvent::Phase(Phase::UpdatingDatabase));
+
+    //Repositories cloned by older versions store names with backslashes
+    sqlite::normalize_names(repository.id, conn)?;
+
+    let local_folders: HashMap<String, RFolder> = sqlite::get_repo_folders(repository.id, conn)?
+        .into_iter()
+        .map(|f| (String::from(&f.name), f))
+        .collect();
+    let local_files: HashMap<String, RFile> = sqlite::get_repo_files(repository.id, conn)?
+        .into_iter()
+        .map(|f| (String::from(&f.name), f))
+        .collect();
+
+    let mut report = UpdateReport {
+        revision: manifest.revision,
+        ..UpdateReport::default()
+    };
+    let local = local_manifest(repository, &local_folders, &local_files)?;
+    for change in diff::diff(&local, manifest) {
+        match change {
+            Change::Added { path, .. } => report.added.push(path),
+            Change::Removed { path, .. } => report.removed.push(path),
+            Change::Modified { path, .. } => report.modified.push(path),
+            Change::Moved { from, to, .. } => report.moved.push((from, to)),
+        }
+    }
+
+    let mut bases: HashMap<String, (String, String)> = HashMap::new();
+    let mut remote_folders: HashSet<&str> = HashSet::new();
+    let mut remote_files: HashSet<&str> = HashSet::new();
+
+    let tx = conn.transaction()?;
+
+    //Repositories cloned before the publisher started signing are pinned on their first signed update
+    if repository.public_key.is_empty() && !manifest.public_key.is_empty() {
+        sqlite::set_public_key(repository.id, &manifest.public_key, &tx)?;
+    }
+
+    // Parents are sorted before their children
+    for (fpath, fse) in &manifest.entries {
+        if fse.is_folder {
+            remote_folders.insert(fpath);
+            if !local_folders.contains_key(fpath) {
+                clone::insert_node(fpath, fse, repository.id, &tx)?;
+            }
+        } else {
+            remote_files.insert(fpath);
+            match local_files.get(fpath) {
+                Some(v) => {
+                    let changed = v.xx_hash64 != fse.hash.to_string()
+                        || (!v.blake2b.is_empty() && v.blake2b != fse.blake2b);
+                    if changed {
+                        sqlite::update_file_hash(v.id, fse.hash, &fse.blake2b, &tx)?;
+                        bases.insert(
+                            String::from(fpath),
+                            (
+                                store::blob_name(&v.xx_hash64, &v.blake2b),
+                                String::from(&v.xx_hash64),
+                            ),
+                        );
+                    } else if v.blake2b != fse.blake2b {
+                        //Cloned from an older manifest, the blob is migrated while downloading
+                        sqlite::update_file_hash(v.id, fse.hash, &fse.blake2b, &tx)?;
+                    }
+                }
+                None => clone::insert_node(fpath, fse, repository.id, &tx)?,
+            }
+        }
+    }
+
+    for (fname, file) in &local_files {
+        if !remote_files.contains(fname.as_str()) {
+            sqlite::delete_file(file.id, &tx)?;
+        }
+    }
+
+    for (fname, folder) in &local_folders {
+        if !folder.is_root && !remote_folders.contains(fname.as_str()) {
+            sqlite::delete_folder(folder.id, &tx)?;
+        }
+    }
+
+    if cancel.is_cancelled() {
+        tx.rollback()?;
+        return Err(UpdateError::Cancelled);
+    }
+    tx.commit()?;
+
+    let stores = store::other_stores(&repository.path, conn)?;
+    let (size, failures) = clone::download_missing(
+        &repository.path,
+        &repository.url,
+        &manifest.entries,
+        &bases,
+        &stores,
+        progress,
+        cancel,
+    )?;
+
+    if cancel.is_cancelled() {
+        return Err(UpdateError::Cancelled);
+    }
+    report.downloaded_bytes = size;
+    report.failures = failures;
+    progress.event(ProgressEvent::Phase(Phase::Finished));
+
+    Ok(report)
+}
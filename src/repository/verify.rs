@@ -0,0 +1,148 @@
+extern crate custom_error;
+use crate::repository::cancel::CancellationToken;
+use crate::repository::clone;
+use crate::repository::clone::{CloneError, DownloadFailure, DownloadTask};
+use crate::repository::hash::ContentHash;
+use crate::repository::progress::NoProgress;
+use crate::repository::store;
+use crate::sql::sqlite;
+use custom_error::custom_error;
+use log::{info, warn};
+use rayon::prelude::*;
+use std::collections::HashMap;
+use std::path::Path;
+use std::time::SystemTime;
+
+extern crate rusqlite;
+
+custom_error! {pub VerifyError
+    SQLError{source: rusqlite::Error} = "SQL Error",
+    SystemTimeErr{source: std::time::SystemTimeError} = "System Time Error",
+    IOError{source: std::io::Error} = "IO Error",
+    CloneError{source: CloneError} = "Clone Error"
+}
+
+/// Result of a verify run, entries are file names (orphans are blob names)
+#[derive(Debug, Default)]
+pub struct VerifyReport {
+    pub missing: Vec<String>,
+    pub corrupt: Vec<String>,
+    pub orphaned: Vec<String>,
+    pub repaired: Vec<String>,
+    pub failures: Vec<DownloadFailure>,
+}
+
+#[derive(Debug, PartialEq)]
+enum BlobState {
+    Ok,
+    Missing,
+    Corrupt,
+}
+
+fn check_blob(path: &str, hash: &ContentHash) -> BlobState {
+    let blob = store::blob_path(path, &hash.blob_name());
+    if !blob.exists() {
+        return BlobState::Missing;
+    }
+
+    match hash.matches(&blob) {
+        Ok(true) => BlobState::Ok,
+        _ => BlobState::Corrupt,
+    }
+}
+
+/// Re-hashes every file of a cloned repository.
+/// * `name` : Repository name (as given to the clone command)
+/// * `repair` : Re-download missing and corrupt files
+pub fn verify(name: &str, repair: bool) -> Result<VerifyReport, VerifyError> {
+    info!(target: "a3mo::verify", "Verifying repository {:?}", &name);
+    let start = SystemTime::now();
+
+    let mut conn = sqlite::get_conn()?;
+    let repository = sqlite::get_repository(name, &mut conn)?;
+
+    // blob -> expected hash
+    let mut hashes: HashMap<String, ContentHash> = HashMap::new();
+    // blob -> file names
+    let mut files: HashMap<String, Vec<String>> = HashMap::new();
+    for file in sqlite::get_repo_files(repository.id, &mut conn)? {
+        let xhash = match file.xx_hash64.parse::<u64>() {
+            Ok(v) => v,
+            Err(_) => continue,
+        };
+        let hash = ContentHash::new(xhash, &file.blake2b);
+        let blob = hash.blob_name();
+        files
+            .entry(String::from(&blob))
+            .or_default()
+            .push(file.name);
+        hashes.insert(blob, hash);
+    }
+
+    let states: Vec<(&String, BlobState)> = hashes
+        .par_iter()
+        .map(|(blob, hash)| (blob, check_blob(&repository.path, hash)))
+        .collect();
+
+    let mut report = VerifyReport::default();
+    let mut bad: Vec<&String> = Vec::new();
+    for (hash, state) in states {
+        match state {
+            BlobState::Ok => continue,
+            BlobState::Missing => report.missing.extend(files[hash].iter().cloned()),
+            BlobState::Corrupt => report.corrupt.extend(files[hash].iter().cloned()),
+        }
+        warn!(target: "a3mo::verify", "{:?} {:?}: {:?}", &state, &hash, &files[hash]);
+        bad.push(hash);
+    }
+
+    let referenced = store::referenced_hashes(&repository.path, &mut conn)?;
+    if Path::new(&repository.path).exists() {
+        for (blob, _) in store::list_blobs(&repository.path)? {
+            if !referenced.contains(&blob) {
+                info!(target: "a3mo::verify", "Orphaned {:?}", &blob);
+                report.orphaned.push(blob);
+            }
+        }
+    }
+
+    if repair && !bad.is_empty() {
+        std::fs::create_dir_all(&repository.path)?;
+
+        let mut tasks: Vec<DownloadTask> = Vec::new();
+        for hash in &bad {
+            let blob = store::blob_path(&repository.path, hash);
+            if blob.exists() {
+                std::fs::remove_file(&blob)?;
+            }
+
+            tasks.push(DownloadTask::new(
+                &repository.path,
+                &repository.url,
+                &files[*hash][0],
+                hashes[*hash].clone(),
+            )?);
+        }
+
+        let (_, failures) = clone::download(&tasks, &NoProgress, &CancellationToken::new());
+        report.failures = failures;
+
+        for hash in &bad {
+            if check_blob(&repository.path, &hashes[*hash]) == BlobState::Ok {
+                report.repaired.extend(files[*hash].iter().cloned());
+            }
+        }
+    }
+
+    info!(
+        target: "a3mo::verify",
+        "Finished verifying. {:?} missing, {:?} corrupt, {:?} orphaned, {:?} repaired in {:?} sec",
+        report.missing.len(),
+        report.corrupt.len(),
+        report.orphaned.len(),
+        report.repaired.len(),
+        start.elapsed()?
+    );
+
+    Ok(report)
+}
@@ -1,5 +1,5 @@
 extern crate rusqlite;
-use crate::repository::build::FileSystemEntity;
+use log::{debug, trace};
 use rusqlite::NO_PARAMS;
 use rusqlite::{Connection, Result};
 
@@ -18,7 +18,9 @@ fn create(conn: &mut Connection) -> Result<()> {
          id integer primary key,\
          name text not null unique,\
          path text not null,\
-         url text not null);",
+         url text not null,\
+         public_key text,\
+         pinned_revision integer);",
         NO_PARAMS,
     )?;
 
@@ -40,6 +42,7 @@ fn create(conn: &mut Connection) -> Result<()> {
          id	INTEGER PRIMARY KEY AUTOINCREMENT,\
          name	INTEGER,\
          xxHash64	TEXT,\
+         blake2b	TEXT,\
          repository_id	INTEGER,\
          parent_id	INTEGER,\
          FOREIGN KEY(parent_id) REFERENCES folder(id),\
@@ -48,28 +51,51 @@ fn create(conn: &mut Connection) -> Result<()> {
         NO_PARAMS,
     )?;
 
+    add_column(&tx, "file", "blake2b", "TEXT")?;
+    add_column(&tx, "repositories", "public_key", "TEXT")?;
+    add_column(&tx, "repositories", "pinned_revision", "INTEGER")?;
+
     tx.commit()?;
 
     Ok(())
 }
 
-pub fn get_file_parent_id(
-    parent_node: &FileSystemEntity,
-    repo_id: i64,
-    conn: &Connection,
-) -> Result<i64> {
-    println!(
+/// Adds `column` to `table`, if the database was created by an older version
+fn add_column(conn: &Connection, table: &str, column: &str, decl: &str) -> Result<()> {
+    let mut stmt = conn.prepare(&format!("PRAGMA table_info({})", table))?;
+    let columns = stmt.query_map(NO_PARAMS, |row| {
+        let name: String = row.get(1)?;
+        Ok(name)
+    })?;
+
+    for c in columns {
+        if c? == column {
+            return Ok(());
+        }
+    }
+
+    debug!(target: "a3mo::sql", "ALTER TABLE {} ADD COLUMN {} {}", table, column, decl);
+    conn.execute(
+        &format!("ALTER TABLE {} ADD COLUMN {} {}", table, column, decl),
+        NO_PARAMS,
+    )?;
+
+    Ok(())
+}
+
+pub fn get_file_parent_id(parent_name: &str, repo_id: i64, conn: &Connection) -> Result<i64> {
+    debug!(
+        target: "a3mo::sql",
         "SELECT id FROM folder WHERE name = {:?} AND repository_id = {:?} LIMIT 1",
-        &parent_node.name, &repo_id
+        parent_name, &repo_id
     );
 
     let mut stmt =
         conn.prepare("SELECT id FROM folder WHERE name = ?1 AND repository_id = ?2 LIMIT 1")?;
 
-    let repo = stmt.query_map(
-        &[String::from(&parent_node.name), repo_id.to_string()],
-        |row| Ok(row.get(0)?),
-    )?;
+    let repo = stmt.query_map(&[String::from(parent_name), repo_id.to_string()], |row| {
+        Ok(row.get(0)?)
+    })?;
 
     match repo.last() {
         Some(x) => x,
@@ -77,23 +103,19 @@ pub fn get_file_parent_id(
     }
 }
 
-pub fn get_folder_parent_id(
-    parent_node: &FileSystemEntity,
-    repo_id: i64,
-    conn: &Connection,
-) -> Result<i64> {
-    println!(
+pub fn get_folder_parent_id(parent_name: &str, repo_id: i64, conn: &Connection) -> Result<i64> {
+    debug!(
+        target: "a3mo::sql",
         "SELECT id FROM folder WHERE name = {:?} AND repository_id = {:?} LIMIT 1",
-        &parent_node.name, &repo_id
+        parent_name, &repo_id
     );
 
     let mut stmt =
         conn.prepare("SELECT id FROM folder WHERE name = ?1 AND repository_id = ?2 LIMIT 1")?;
 
-    let repo = stmt.query_map(
-        &[String::from(&parent_node.name), repo_id.to_string()],
-        |row| Ok(row.get(0)?),
-    )?;
+    let repo = stmt.query_map(&[String::from(parent_name), repo_id.to_string()], |row| {
+        Ok(row.get(0)?)
+    })?;
 
     match repo.last() {
         Some(x) => x,
@@ -104,19 +126,21 @@ pub fn get_folder_parent_id(
 pub fn insert_file(
     name: &str,
     xx_hash: u64,
+    blake2b: &str,
     repo_id: i64,
     parent_id: i64,
     conn: &Connection,
 ) -> Result<()> {
     let xx = xx_hash.to_string();
-    println!("{:?}", &xx.as_str());
+    trace!(target: "a3mo::sql", "{:?}", &xx.as_str());
     conn.execute(
         "INSERT INTO file \
-         (id, name, xxHash64, repository_id, parent_id) \
-         VALUES (NULL, ?1, ?2, ?3, ?4)",
+         (id, name, xxHash64, blake2b, repository_id, parent_id) \
+         VALUES (NULL, ?1, ?2, ?3, ?4, ?5)",
         &[
             name,
             &xx.as_str(),
+            blake2b,
             repo_id.to_string().as_str(),
             parent_id.to_string().as_str(),
         ],
@@ -167,13 +191,18 @@ pub struct Repository {
     pub name: String,
     pub path: String,
     pub url: String,
+    /// Publisher key pinned on clone, empty if the repository is not signed
+    pub public_key: String,
+    /// Revision checked out by the checkout command, update skips pinned repositories
+    pub pinned_revision: Option<u64>,
 }
 
 pub fn get_repository(name: &str, mut conn: &mut Connection) -> Result<Repository> {
     create(&mut conn)?;
 
-    let mut stmt =
-        conn.prepare("SELECT id, name, path, url FROM repositories WHERE name = ?1 LIMIT 1")?;
+    let mut stmt = conn.prepare(
+        "SELECT id, name, path, url, public_key, pinned_revision FROM repositories WHERE name = ?1 LIMIT 1",
+    )?;
 
     let repo = stmt.query_map(&[name], |row| {
         Ok(Repository {
@@ -181,6 +210,8 @@ pub fn get_repository(name: &str, mut conn: &mut Connection) -> Result<Repositor
             name: row.get(1)?,
             path: row.get(2)?,
             url: row.get(3)?,
+            public_key: row.get::<_, Option<String>>(4)?.unwrap_or_default(),
+            pinned_revision: row.get::<_, Option<i64>>(5)?.map(|v| v as u64),
         })
     })?;
 
@@ -223,19 +254,23 @@ pub struct RFile {
     pub id: i64,
     pub name: String,
     pub xx_hash64: String,
+    /// Empty for files cloned from manifests without BLAKE2b digests
+    pub blake2b: String,
     pub parent_id: i64,
 }
 
 pub fn get_repo_files(repo_id: i64, conn: &mut Connection) -> Result<Vec<RFile>> {
-    let mut stmt =
-        conn.prepare("SELECT id, name, xxHash64, parent_id FROM file WHERE repository_id = ?1")?;
+    let mut stmt = conn.prepare(
+        "SELECT id, name, xxHash64, blake2b, parent_id FROM file WHERE repository_id = ?1",
+    )?;
 
     let files = stmt.query_map(&[repo_id], |row| {
         Ok(RFile {
             id: row.get(0)?,
             name: row.get(1)?,
             xx_hash64: row.get(2)?,
-            parent_id: row.get(3)?,
+            blake2b: row.get::<_, Option<String>>(3)?.unwrap_or_default(),
+            parent_id: row.get(4)?,
         })
     })?;
 
@@ -243,3 +278,91 @@ pub fn get_repo_files(repo_id: i64, conn: &mut Connection) -> Result<Vec<RFile>>
 
     Ok(_repo_files)
 }
+
+pub fn update_file_hash(id: i64, xx_hash: u64, blake2b: &str, conn: &Connection) -> Result<()> {
+    conn.execute(
+        "UPDATE file SET xxHash64 = ?1, blake2b = ?2 WHERE id = ?3",
+        &[
+            xx_hash.to_string().as_str(),
+            blake2b,
+            id.to_string().as_str(),
+        ],
+    )?;
+
+    Ok(())
+}
+
+/// Replaces the backslashes in folder and file names stored by older versions with '/'
+pub fn normalize_names(repo_id: i64, conn: &Connection) -> Result<()> {
+    conn.execute(
+        "UPDATE folder SET name = REPLACE(name, '\\', '/') WHERE repository_id = ?1",
+        &[repo_id],
+    )?;
+    conn.execute(
+        "UPDATE file SET name = REPLACE(name, '\\', '/') WHERE repository_id = ?1",
+        &[repo_id],
+    )?;
+
+    Ok(())
+}
+
+pub fn delete_file(id: i64, conn: &Connection) -> Result<()> {
+    conn.execute("DELETE FROM file WHERE id = ?1", &[id])?;
+
+    Ok(())
+}
+
+pub fn delete_folder(id: i64, conn: &Connection) -> Result<()> {
+    conn.execute("DELETE FROM folder WHERE id = ?1", &[id])?;
+
+    Ok(())
+}
+
+pub fn get_repositories(mut conn: &mut Connection) -> Result<Vec<Repository>> {
+    create(&mut conn)?;
+
+    let mut stmt =
+        conn.prepare("SELECT id, name, path, url, public_key, pinned_revision FROM repositories")?;
+
+    let repos = stmt.query_map(NO_PARAMS, |row| {
+        Ok(Repository {
+            id: row.get(0)?,
+            name: row.get(1)?,
+            path: row.get(2)?,
+            url: row.get(3)?,
+            public_key: row.get::<_, Option<String>>(4)?.unwrap_or_default(),
+            pinned_revision: row.get::<_, Option<i64>>(5)?.map(|v| v as u64),
+        })
+    })?;
+
+    let _repos: Vec<Repository> = repos.map(|r| r.unwrap()).collect();
+
+    Ok(_repos)
+}
+
+pub fn set_public_key(id: i64, public_key: &str, conn: &Connection) -> Result<()> {
+    conn.execute(
+        "UPDATE repositories SET public_key = ?1 WHERE id = ?2",
+        &[public_key, id.to_string().as_str()],
+    )?;
+
+    Ok(())
+}
+
+pub fn set_pinned_revision(id: i64, revision: Option<u64>, conn: &Connection) -> Result<()> {
+    conn.execute(
+        "UPDATE repositories SET pinned_revision = ?1 WHERE id = ?2",
+        &[revision.map(|v| v as i64), Some(id)],
+    )?;
+
+    Ok(())
+}
+
+/// Deletes the repository with all of its folder and file rows
+pub fn delete_repository(id: i64, conn: &Connection) -> Result<()> {
+    conn.execute("DELETE FROM file WHERE repository_id = ?1", &[id])?;
+    conn.execute("DELETE FROM folder WHERE repository_id = ?1", &[id])?;
+    conn.execute("DELETE FROM repositories WHERE id = ?1", &[id])?;
+
+    Ok(())
+}